@@ -0,0 +1,427 @@
+use std::mem;
+
+use slab::{Slab, NIL};
+use StaleToken;
+
+/// A handle to a value in an `ArenaHeap`, returned by `push`.
+///
+/// The token is plain data: an index into the arena and the generation
+/// of the slot at that index. It can be stored, copied or serialized
+/// via `into_raw` and `from_raw` freely. A token for a value that has
+/// been popped is detected through its generation. Using a token with
+/// an arena other than the one that issued it is memory safe, but may
+/// refer to an unrelated value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ArenaToken {
+    index: u32,
+    generation: u32,
+}
+
+impl ArenaToken {
+    /// The index and generation that make up this token.
+    pub fn into_raw(self) -> (u32, u32) {
+        (self.index, self.generation)
+    }
+
+    /// Rebuilds a token from the parts returned by `into_raw`.
+    pub fn from_raw(index: u32, generation: u32) -> Self {
+        ArenaToken { index, generation }
+    }
+}
+
+struct Links {
+    first_child: u32,
+    prev: u32,
+    next: u32,
+}
+
+impl Default for Links {
+    fn default() -> Self {
+        Links { first_child: NIL, prev: NIL, next: NIL }
+    }
+}
+
+/// A pairing heap that keeps its nodes in a single `Vec`, linking them
+/// by `u32` index rather than by pointer. Popped slots are reused, so a
+/// heap that stays around the same size stops allocating.
+pub struct ArenaHeap<T> {
+    slab: Slab<T, Links>,
+    root: u32,
+    tree_array: Vec<u32>,
+}
+
+impl<T> ArenaHeap<T>
+    where T: Ord,
+{
+    pub fn new() -> ArenaHeap<T> {
+        ArenaHeap::with_capacity(0)
+    }
+
+    /// Creates a heap that can hold `capacity` values before it needs
+    /// to grow the arena.
+    pub fn with_capacity(capacity: usize) -> ArenaHeap<T> {
+        ArenaHeap {
+            slab: Slab::with_capacity(capacity),
+            root: NIL,
+            tree_array: Vec::with_capacity(5),
+        }
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the arena would need more than `u32::MAX - 1` slots.
+    pub fn push(&mut self, value: T) -> ArenaToken {
+        // Compare before allocating, in case it panics.
+        let before_root = match self.root {
+            NIL => false,
+            root => value < *self.slab.value(root),
+        };
+        let index = self.slab.insert(value);
+
+        self.root = if self.root == NIL {
+            index
+        } else {
+            self.link(self.root, index, before_root)
+        };
+
+        ArenaToken {
+            index,
+            generation: self.slab.generation(index),
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.root == NIL { return None }
+
+        // The root stays in place until its children are combined, so
+        // a panicking comparison can hang them back below it.
+        let root = self.root;
+        let first_child = mem::replace(&mut self.slab[root].first_child, NIL);
+        self.root = self.combine_siblings(first_child);
+
+        Some(self.slab.remove(root))
+    }
+
+    /// Do not increase the key!
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped.
+    pub fn decrease_key<F>(&mut self, token: ArenaToken, f: F)
+        where F: FnOnce(&mut T),
+    {
+        if let Err(e) = self.try_decrease_key(token, f) {
+            panic!("Unable to decrease key: {}", e);
+        }
+    }
+
+    /// Like `decrease_key`, but reports a popped token instead of
+    /// panicking. The closure is not called in that case.
+    pub fn try_decrease_key<F>(&mut self, token: ArenaToken, f: F) -> Result<(), StaleToken>
+        where F: FnOnce(&mut T),
+    {
+        let node = self.resolve(token)?;
+
+        // Apply the change that decreases the key
+        f(self.slab.value_mut(node));
+
+        if node == self.root { return Ok(()) }
+
+        // Compare before cutting the node out, in case it panics.
+        let before_root = self.comes_before(node, self.root);
+        let (prev, next) = {
+            let node_r = &self.slab[node];
+            (node_r.prev, node_r.next)
+        };
+
+        if next != NIL {
+            self.slab[next].prev = prev;
+        }
+
+        if self.slab[prev].first_child == node {
+            self.slab[prev].first_child = next;
+        } else {
+            self.slab[prev].next = next;
+        }
+
+        self.slab[node].next = NIL;
+        self.root = self.link(self.root, node, before_root);
+
+        Ok(())
+    }
+
+    fn resolve(&self, token: ArenaToken) -> Result<u32, StaleToken> {
+        self.slab.resolve(token.index, token.generation)
+    }
+
+    /// Whether `a` should be popped before `b`.
+    fn comes_before(&self, a: u32, b: u32) -> bool {
+        self.slab.value(a) < self.slab.value(b)
+    }
+
+    fn compare_and_link(&mut self, first: u32, second: u32) -> u32 {
+        if second == NIL { return first }
+
+        let second_first = self.comes_before(second, first);
+        self.link(first, second, second_first)
+    }
+
+    /// Links two trees whose order has already been decided, so nothing
+    /// here can panic halfway.
+    fn link(&mut self, first: u32, second: u32, second_first: bool) -> u32 {
+        if second_first {
+            self.slab[second].prev = self.slab[first].prev;
+            self.slab[first].prev = second;
+            let first_next = self.slab[second].first_child;
+            self.slab[first].next = first_next;
+            if first_next != NIL {
+                self.slab[first_next].prev = first;
+            }
+            self.slab[second].first_child = first;
+            second
+        } else {
+            self.slab[second].prev = first;
+            let first_next = self.slab[second].next;
+            self.slab[first].next = first_next;
+            if first_next != NIL {
+                self.slab[first_next].prev = first;
+            }
+            let second_next = self.slab[first].first_child;
+            self.slab[second].next = second_next;
+            if second_next != NIL {
+                self.slab[second_next].prev = second;
+            }
+            self.slab[first].first_child = second;
+            first
+        }
+    }
+
+    /// The same two-pass combining as `TwoPass`, over indices.
+    fn combine_siblings(&mut self, mut first_sibling: u32) -> u32 {
+        if first_sibling == NIL || self.slab[first_sibling].next == NIL {
+            if first_sibling != NIL {
+                self.slab[first_sibling].prev = NIL;
+            }
+            return first_sibling;
+        }
+
+        let mut detached = Detached::new(self);
+
+        while first_sibling != NIL {
+            let sibling_r = &mut detached.heap.slab[first_sibling];
+            let next = sibling_r.next;
+            sibling_r.prev = NIL;
+            sibling_r.next = NIL;
+
+            detached.heap.tree_array.push(first_sibling);
+            first_sibling = next;
+        }
+
+        // Pad with a NIL to ensure all siblings are in an even amount
+        detached.heap.tree_array.push(NIL);
+        let logical_length = detached.heap.tree_array.len() / 2 * 2;
+
+        for idx in (0..logical_length).step_by(2) {
+            detached.link(idx, idx + 1);
+        }
+
+        if logical_length >= 4 {
+            let mut end_idx = logical_length - 2;
+
+            while end_idx >= 2 {
+                detached.link(end_idx - 2, end_idx);
+                end_idx -= 2;
+            }
+        }
+
+        detached.finish()
+    }
+}
+
+/// Sibling trees cut loose by `pop`, kept in `tree_array` until they
+/// are linked. If a comparison panics, dropping this hangs the trees
+/// that are left below the root again, so no value is lost.
+struct Detached<'a, T: 'a + Ord> {
+    heap: &'a mut ArenaHeap<T>,
+}
+
+impl<'a, T> Detached<'a, T>
+    where T: Ord,
+{
+    fn new(heap: &'a mut ArenaHeap<T>) -> Self {
+        debug_assert!(heap.tree_array.is_empty());
+        Detached { heap }
+    }
+
+    /// Links the tree at `from` into the one at `into`. `from` is only
+    /// emptied once the comparison is over.
+    fn link(&mut self, into: usize, from: usize) {
+        let (first, second) = (self.heap.tree_array[into], self.heap.tree_array[from]);
+        self.heap.tree_array[into] = self.heap.compare_and_link(first, second);
+        self.heap.tree_array[from] = NIL;
+    }
+
+    /// Takes the single tree left in the first slot.
+    fn finish(self) -> u32 {
+        let root = mem::replace(&mut self.heap.tree_array[0], NIL);
+        self.heap.tree_array.clear();
+        root
+    }
+}
+
+impl<'a, T> Drop for Detached<'a, T>
+    where T: Ord,
+{
+    fn drop(&mut self) {
+        let heap = &mut *self.heap;
+        let mut trees = mem::take(&mut heap.tree_array);
+
+        for &tree in &trees {
+            if tree == NIL { continue }
+
+            if heap.root == NIL {
+                heap.root = tree;
+            } else {
+                let root = heap.root;
+                heap.link(root, tree, false);
+            }
+        }
+
+        trees.clear();
+        heap.tree_array = trees;
+    }
+}
+
+impl<T> Default for ArenaHeap<T>
+    where T: Ord,
+{
+    fn default() -> Self {
+        ArenaHeap::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use unwind::{self, Fragile};
+    use {ArenaHeap, ArenaToken, StaleToken};
+
+    /// Runs `op` with comparisons that panic after 0, 1, 2, ... of
+    /// them, until it finishes. `op` counts the values it pops less
+    /// those it pushes, and every other value must still be in the heap.
+    fn check_unwinding<F>(op: F)
+        where F: Fn(&mut ArenaHeap<Fragile<u32>>, &[ArenaToken], &Cell<i32>),
+    {
+        unwind::check(|| {
+            let mut h = ArenaHeap::new();
+            let tokens: Vec<_> = (0..20).map(|i| h.push(Fragile(i * 7 % 20 + 100))).collect();
+            h.pop();
+            (h, tokens, Cell::new(1))
+        }, |&mut (ref mut h, ref tokens, ref popped)| {
+            op(h, tokens, popped)
+        }, |(mut h, _, popped)| {
+            while h.pop().is_some() { popped.set(popped.get() + 1); }
+            assert_eq!(20, popped.get());
+        });
+    }
+
+    #[test]
+    fn panicking_comparison_during_pop_keeps_every_value() {
+        check_unwinding(|h, _, popped| {
+            while h.pop().is_some() { popped.set(popped.get() + 1); }
+        });
+    }
+
+    #[test]
+    fn panicking_comparison_during_push_keeps_every_value() {
+        check_unwinding(|h, _, popped| {
+            for i in 0..10 {
+                h.push(Fragile(i));
+                popped.set(popped.get() - 1);
+            }
+        });
+    }
+
+    #[test]
+    fn panicking_comparison_during_decrease_key_keeps_every_value() {
+        check_unwinding(|h, tokens, popped| {
+            h.pop();
+            popped.set(popped.get() + 1);
+            for &t in tokens.iter().skip(1).step_by(3) {
+                h.decrease_key(t, |v| v.0 -= 50);
+            }
+        });
+    }
+
+    #[test]
+    fn empty_heap_pops_none() {
+        let mut h = ArenaHeap::<u8>::new();
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn many_values_inserted_returns_them_in_order() {
+        let mut h = ArenaHeap::new();
+        for i in (0..123).rev() { h.push(i); }
+        for i in 0..123 { h.push(i); }
+
+        for i in 0..123 {
+            assert_eq!(Some(i), h.pop());
+            assert_eq!(Some(i), h.pop());
+        }
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn decreasing_a_key_of_many_brings_it_to_the_front() {
+        let mut h = ArenaHeap::new();
+
+        let mut t = h.push(10);
+        for i in 11..100 {
+            t = h.push(i);
+        }
+
+        h.decrease_key(t, |v| *v = 1);
+
+        assert_eq!(Some(1), h.pop());
+        for i in 10..99 {
+            assert_eq!(Some(i), h.pop());
+        }
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn popped_slots_are_reused() {
+        let mut h = ArenaHeap::new();
+
+        let old = h.push(10);
+        assert_eq!(Some(10), h.pop());
+        let new = h.push(20);
+
+        assert_eq!(old.into_raw().0, new.into_raw().0);
+        assert_eq!(Err(StaleToken), h.try_decrease_key(old, |v| *v = 1));
+        assert_eq!(Ok(()), h.try_decrease_key(new, |v| *v = 5));
+        assert_eq!(Some(5), h.pop());
+    }
+
+    #[test]
+    fn tokens_round_trip_through_raw_parts() {
+        let mut h = ArenaHeap::new();
+
+        h.push(10);
+        let t = h.push(20);
+        let (index, generation) = t.into_raw();
+
+        h.decrease_key(ArenaToken::from_raw(index, generation), |v| *v = 5);
+        assert_eq!(Some(5), h.pop());
+    }
+
+    #[test]
+    fn unknown_tokens_are_an_error() {
+        let mut h = ArenaHeap::new();
+        h.push(10);
+
+        assert_eq!(Err(StaleToken), h.try_decrease_key(ArenaToken::from_raw(7, 0), |v| *v = 5));
+    }
+}
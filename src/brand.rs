@@ -0,0 +1,172 @@
+use std::marker::PhantomData;
+
+use {CombineStrategy, Compare, Heap, Min, StaleToken, Token, TwoPass};
+
+/// An invariant lifetime that is unique to a single call of
+/// `Heap::scope`. No two scopes can ever agree on it, so anything
+/// carrying a `Brand<'id>` can only meet other things from the same
+/// scope.
+#[derive(Copy, Clone)]
+struct Brand<'id>(PhantomData<fn(&'id ()) -> &'id ()>);
+
+/// A `Heap` whose tokens are branded with a lifetime unique to it,
+/// created by `Heap::scope`.
+///
+/// Because a `BrandedToken` can only be used with the heap that issued
+/// it, no runtime heap identity check is needed. A token for a value
+/// that has since been popped is still detected through its
+/// generation.
+pub struct BrandedHeap<'id, T, C = Min, S = TwoPass> {
+    heap: Heap<T, C, S>,
+    _brand: Brand<'id>,
+}
+
+/// A handle to a value in a `BrandedHeap`, returned by `push`.
+pub struct BrandedToken<'id, T> {
+    token: Token<T>,
+    _brand: Brand<'id>,
+}
+
+impl<'id, T> Copy for BrandedToken<'id, T> {}
+impl<'id, T> Clone for BrandedToken<'id, T> {
+    fn clone(&self) -> Self { *self }
+}
+
+impl<T, C, S> Heap<T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    /// Runs `f` with a branded view of this heap. Tokens issued inside
+    /// the scope cannot be used with any other heap, which the
+    /// compiler checks:
+    ///
+    /// ```
+    /// use pairing_heap::Heap;
+    ///
+    /// let min = Heap::new().scope(|mut heap| {
+    ///     heap.push(10);
+    ///     let t = heap.push(20);
+    ///     heap.decrease_key(t, |v| *v = 5);
+    ///     heap.pop()
+    /// });
+    /// assert_eq!(Some(5), min);
+    /// ```
+    ///
+    /// ```compile_fail
+    /// use pairing_heap::Heap;
+    ///
+    /// Heap::<i32>::new().scope(|mut a| {
+    ///     Heap::<i32>::new().scope(|mut b| {
+    ///         let t = a.push(10);
+    ///         b.decrease_key(t, |v| *v = 5);
+    ///     });
+    /// });
+    /// ```
+    pub fn scope<F, R>(self, f: F) -> R
+        where F: for<'id> FnOnce(BrandedHeap<'id, T, C, S>) -> R,
+    {
+        f(BrandedHeap {
+            heap: self,
+            _brand: Brand(PhantomData),
+        })
+    }
+}
+
+impl<'id, T, C, S> BrandedHeap<'id, T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    pub fn push(&mut self, value: T) -> BrandedToken<'id, T> {
+        BrandedToken {
+            token: self.heap.push(value),
+            _brand: self._brand,
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.heap.pop()
+    }
+
+    /// The number of values in the heap.
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    /// Do not increase the key!
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped.
+    pub fn decrease_key<F>(&mut self, token: BrandedToken<'id, T>, f: F)
+        where F: FnOnce(&mut T),
+    {
+        if let Err(e) = self.try_decrease_key(token, f) {
+            panic!("Unable to decrease key: {}", e);
+        }
+    }
+
+    /// Like `decrease_key`, but reports a popped token instead of
+    /// panicking. The closure is not called in that case.
+    pub fn try_decrease_key<F>(&mut self, token: BrandedToken<'id, T>, f: F) -> Result<(), StaleToken>
+        where F: FnOnce(&mut T),
+    {
+        // The brand guarantees the token came from this heap.
+        let node = unsafe { self.heap.resolve_generation(token.token)? };
+        self.heap.decrease_node(node, f);
+        Ok(())
+    }
+
+    /// Gives up the brand, returning the underlying heap.
+    pub fn into_inner(self) -> Heap<T, C, S> {
+        self.heap
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {Heap, StaleToken};
+
+    #[test]
+    fn branded_heap_behaves_like_a_heap() {
+        let values = Heap::new().scope(|mut h| {
+            h.push(10);
+            let t = h.push(20);
+            h.push(30);
+
+            h.decrease_key(t, |v| *v = 5);
+
+            let mut values = Vec::new();
+            while let Some(v) = h.pop() { values.push(v) }
+            values
+        });
+
+        assert_eq!(vec![5, 10, 30], values);
+    }
+
+    #[test]
+    fn branded_tokens_detect_popped_values() {
+        Heap::new().scope(|mut h| {
+            let t = h.push(10);
+            assert_eq!(Some(10), h.pop());
+
+            assert_eq!(Err(StaleToken), h.try_decrease_key(t, |v| *v = 5));
+        });
+    }
+
+    #[test]
+    fn into_inner_keeps_the_values() {
+        let mut h = Heap::new().scope(|mut h| {
+            h.push(2);
+            h.push(1);
+            h.into_inner()
+        });
+
+        assert_eq!(Some(1), h.pop());
+        assert_eq!(Some(2), h.pop());
+        assert_eq!(None, h.pop());
+    }
+}
@@ -0,0 +1,160 @@
+use std::iter::FromIterator;
+
+use {CombineStrategy, Compare, Detached, Heap, Token};
+
+impl<T, C, S> Heap<T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    /// Pushes every value, returning a token for each in the same
+    /// order.
+    pub fn extend_with_tokens<I>(&mut self, iter: I) -> Vec<Token<T>>
+        where I: IntoIterator<Item = T>,
+    {
+        let mut tokens = Vec::new();
+        self.push_all(iter, |token| tokens.push(token));
+        tokens
+    }
+
+    /// Pushes every value, then links them all as children of whichever
+    /// comes first.
+    fn push_all<I, F>(&mut self, iter: I, mut f: F)
+        where I: IntoIterator<Item = T>,
+              F: FnMut(Token<T>),
+    {
+        let mut detached = Detached::new(self);
+
+        for value in iter {
+            let node = detached.heap.allocate(value);
+            detached.heap.len += 1;
+            detached.push(node);
+            f(detached.heap.token(node));
+        }
+
+        detached.link_as_children();
+    }
+}
+
+/// Each value costs a single comparison to find the new root, and all
+/// of them become its children; combining those children is left until
+/// the next `pop`. Building a heap of n values is therefore O(n).
+impl<T, C, S> Extend<T> for Heap<T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    fn extend<I>(&mut self, iter: I)
+        where I: IntoIterator<Item = T>,
+    {
+        self.push_all(iter, |_| {});
+    }
+}
+
+impl<'a, T, C, S> Extend<&'a T> for Heap<T, C, S>
+    where T: 'a + Copy,
+          C: Compare<T>,
+          S: CombineStrategy,
+{
+    fn extend<I>(&mut self, iter: I)
+        where I: IntoIterator<Item = &'a T>,
+    {
+        self.extend(iter.into_iter().cloned());
+    }
+}
+
+impl<T, C, S> FromIterator<T> for Heap<T, C, S>
+    where C: Compare<T> + Default,
+          S: CombineStrategy + Default,
+{
+    fn from_iter<I>(iter: I) -> Self
+        where I: IntoIterator<Item = T>,
+    {
+        let mut heap = Heap::with_strategy(C::default(), S::default());
+        heap.extend(iter);
+        heap
+    }
+}
+
+impl<T, C, S> From<Vec<T>> for Heap<T, C, S>
+    where C: Compare<T> + Default,
+          S: CombineStrategy + Default,
+{
+    fn from(values: Vec<T>) -> Self {
+        values.into_iter().collect()
+    }
+}
+
+impl<T, C, S, const N: usize> From<[T; N]> for Heap<T, C, S>
+    where C: Compare<T> + Default,
+          S: CombineStrategy + Default,
+{
+    fn from(values: [T; N]) -> Self {
+        IntoIterator::into_iter(values).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {Heap, Max};
+
+    #[test]
+    fn collecting_into_a_heap() {
+        let h: Heap<_> = (0..10).rev().collect();
+
+        assert_eq!(10, h.len());
+        assert_eq!((0..10).collect::<Vec<_>>(), h.into_sorted_vec());
+    }
+
+    #[test]
+    fn collecting_with_a_default_comparator() {
+        let h: Heap<_, Max> = (0..10).collect();
+
+        assert_eq!((0..10).rev().collect::<Vec<_>>(), h.into_sorted_vec());
+    }
+
+    #[test]
+    fn extending_a_heap() {
+        let mut h = Heap::new();
+        h.push(5);
+
+        h.extend(vec![3, 7]);
+        h.extend(&[1, 9]);
+
+        assert_eq!(vec![1, 3, 5, 7, 9], h.into_sorted_vec());
+    }
+
+    #[test]
+    fn extending_hangs_every_value_below_the_root() {
+        let mut h = Heap::new();
+        h.push(50);
+        h.extend((0..20).rev());
+
+        let mut children = 0;
+        let mut child = unsafe { (*h.root).first_child };
+        while !child.is_null() {
+            assert!(unsafe { (*child).first_child.is_null() });
+            children += 1;
+            child = unsafe { (*child).next };
+        }
+        assert_eq!(20, children);
+        assert_eq!(Some(&0), h.peek());
+    }
+
+    #[test]
+    fn converting_from_vecs_and_arrays() {
+        let h: Heap<_> = Heap::from(vec![3, 1, 2]);
+        assert_eq!(vec![1, 2, 3], h.into_sorted_vec());
+
+        let h: Heap<_> = Heap::from([3, 1, 2]);
+        assert_eq!(vec![1, 2, 3], h.into_sorted_vec());
+    }
+
+    #[test]
+    fn extending_with_tokens() {
+        let mut h = Heap::new();
+
+        let tokens = h.extend_with_tokens(vec![10, 20, 30]);
+        h.decrease_key(tokens[2], |v| *v = 0);
+
+        assert_eq!(vec![0, 10, 20], h.into_sorted_vec());
+    }
+}
@@ -0,0 +1,227 @@
+use std::ptr;
+
+use {compare_and_link, Compare, Node};
+
+/// Decides how the children of a removed or changed node are combined
+/// back into a single tree.
+///
+/// The children are handed over as a `Forest`, in sibling order, so
+/// the child linked most recently comes first. A strategy links them
+/// with `Forest::link` until only the tree in slot 0 is left. Any tree
+/// left in another slot is linked with the root afterwards, which is
+/// correct but defeats the point of the strategy.
+pub trait CombineStrategy {
+    fn combine<T, C>(&self, forest: &mut Forest<'_, T, C>)
+        where C: Compare<T>;
+}
+
+/// The trees being combined by a `CombineStrategy`, each in its own
+/// slot.
+pub struct Forest<'a, T: 'a, C: 'a> {
+    trees: &'a mut [*mut Node<T>],
+    compare: &'a C,
+    stable: bool,
+}
+
+impl<'a, T, C> Forest<'a, T, C>
+    where C: Compare<T>,
+{
+    pub(crate) fn new(trees: &'a mut [*mut Node<T>], compare: &'a C, stable: bool) -> Self {
+        Forest { trees, compare, stable }
+    }
+
+    /// The number of slots, including those already emptied by `link`.
+    pub fn len(&self) -> usize {
+        self.trees.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trees.is_empty()
+    }
+
+    /// Links the tree in slot `from` with the tree in slot `into`,
+    /// leaving the result in `into` and emptying `from`. Either slot
+    /// may already be empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either slot is out of bounds or both are the same.
+    pub fn link(&mut self, into: usize, from: usize) {
+        assert!(into != from, "Unable to link a tree with itself");
+
+        let first = self.trees[into];
+        let second = self.trees[from];
+
+        // `from` is only emptied once the comparison is over, so a
+        // panicking comparator leaves both trees in the forest.
+        self.trees[into] = if first.is_null() {
+            second
+        } else {
+            compare_and_link(self.compare, self.stable, first, second)
+        };
+        self.trees[from] = ptr::null_mut();
+    }
+}
+
+/// Links neighbouring pairs from front to back, then folds the results
+/// together from back to front. This is the classic pairing heap and
+/// the default.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TwoPass;
+
+impl CombineStrategy for TwoPass {
+    fn combine<T, C>(&self, forest: &mut Forest<'_, T, C>)
+        where C: Compare<T>,
+    {
+        let len = forest.len();
+        if len < 2 { return }
+
+        // Walk forward in pairs, leaving the result in the {0,2,4,...}
+        // indexes. Then walk backward across the {[2,4], [0,2]} results,
+        // leaving the result in the first index. The final result will be
+        // in index 0.
+
+        // 0     1  2   3  4
+        // ----------------
+        // 1     2  3   4  5
+        // 12    N  3   4  5
+        // 12    N  34  N  5 -- forward pass done
+        // 12    N  345 N  N
+        // 12345 N  N   N  N -- backward pass done
+
+        let mut idx = 0;
+        while idx + 1 < len {
+            forest.link(idx, idx + 1);
+            idx += 2;
+        }
+
+        let mut end_idx = (len - 1) / 2 * 2;
+        while end_idx >= 2 {
+            forest.link(end_idx - 2, end_idx);
+            end_idx -= 2;
+        }
+    }
+}
+
+/// Links neighbouring pairs over and over, halving the number of trees
+/// each round, until one is left.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Multipass;
+
+impl CombineStrategy for Multipass {
+    fn combine<T, C>(&self, forest: &mut Forest<'_, T, C>)
+        where C: Compare<T>,
+    {
+        let len = forest.len();
+        let mut step = 1;
+
+        while step < len {
+            let mut idx = 0;
+            while idx + step < len {
+                forest.link(idx, idx + step);
+                idx += 2 * step;
+            }
+            step *= 2;
+        }
+    }
+}
+
+/// Links every tree into the first one in turn.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct FrontToBack;
+
+impl CombineStrategy for FrontToBack {
+    fn combine<T, C>(&self, forest: &mut Forest<'_, T, C>)
+        where C: Compare<T>,
+    {
+        for idx in 1..forest.len() {
+            forest.link(0, idx);
+        }
+    }
+}
+
+/// Links the last tree into the one before it, and so on until the
+/// first.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct BackToFront;
+
+impl CombineStrategy for BackToFront {
+    fn combine<T, C>(&self, forest: &mut Forest<'_, T, C>)
+        where C: Compare<T>,
+    {
+        for idx in (1..forest.len()).rev() {
+            forest.link(idx - 1, idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BackToFront, CombineStrategy, Forest, FrontToBack, Multipass, TwoPass};
+    use std::cell::Cell;
+    use {Compare, Heap, Min};
+
+    fn check_strategy<S>(strategy: S)
+        where S: CombineStrategy,
+    {
+        let mut h = Heap::with_strategy(Min, strategy);
+        let tokens: Vec<_> = (0..200).map(|i| h.push(i * 37 % 200 + 1000)).collect();
+
+        for (i, &t) in tokens.iter().enumerate().step_by(3) {
+            h.decrease_key(t, |v| *v = i);
+        }
+        for &t in tokens.iter().skip(1).step_by(7) {
+            h.increase_key(t, |v| *v += 500);
+        }
+        h.remove(tokens[10]);
+
+        let mut expected: Vec<_> = tokens.iter().filter_map(|&t| h.try_get(t).cloned()).collect();
+        expected.sort();
+
+        assert_eq!(expected, h.into_sorted_vec());
+    }
+
+    #[test]
+    fn two_pass_pops_in_order() {
+        check_strategy(TwoPass);
+    }
+
+    #[test]
+    fn multipass_pops_in_order() {
+        check_strategy(Multipass);
+    }
+
+    #[test]
+    fn front_to_back_pops_in_order() {
+        check_strategy(FrontToBack);
+    }
+
+    #[test]
+    fn back_to_front_pops_in_order() {
+        check_strategy(BackToFront);
+    }
+
+    #[test]
+    fn trees_left_over_by_a_strategy_are_still_linked() {
+        /// Links only the first two trees, counting how often it runs.
+        struct Lazy<'a>(&'a Cell<usize>);
+
+        impl<'a> CombineStrategy for Lazy<'a> {
+            fn combine<T, C>(&self, forest: &mut Forest<'_, T, C>)
+                where C: Compare<T>,
+            {
+                self.0.set(self.0.get() + 1);
+                if forest.len() >= 2 {
+                    forest.link(0, 1);
+                }
+            }
+        }
+
+        let runs = Cell::new(0);
+        let mut h = Heap::with_strategy(Min, Lazy(&runs));
+        h.extend(vec![5, 3, 8, 1, 9, 2, 7]);
+
+        assert_eq!(vec![1, 2, 3, 5, 7, 8, 9], h.into_sorted_vec());
+        assert!(runs.get() > 0);
+    }
+}
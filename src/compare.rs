@@ -0,0 +1,54 @@
+use std::cmp::Ordering;
+
+/// Decides the order of values in a heap. The value that compares as
+/// `Less` than every other is the one popped first.
+///
+/// Any closure `Fn(&T, &T) -> Ordering` is a comparator, which allows
+/// ordering by a single field or by something only known at runtime.
+pub trait Compare<T> {
+    fn compare(&self, a: &T, b: &T) -> Ordering;
+}
+
+impl<T, F> Compare<T> for F
+    where F: Fn(&T, &T) -> Ordering,
+{
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        self(a, b)
+    }
+}
+
+/// Orders values by `Ord`, smallest first.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Min;
+
+impl<T> Compare<T> for Min
+    where T: Ord,
+{
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Orders values by `Ord`, largest first.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Max;
+
+impl<T> Compare<T> for Max
+    where T: Ord,
+{
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+/// Orders `(priority, value)` entries by their priority alone.
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct ByPriority;
+
+impl<P, V> Compare<(P, V)> for ByPriority
+    where P: Ord,
+{
+    fn compare(&self, a: &(P, V), b: &(P, V)) -> Ordering {
+        a.0.cmp(&b.0)
+    }
+}
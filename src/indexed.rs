@@ -0,0 +1,253 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::hash::Hash;
+
+use compare::ByPriority;
+use {Heap, Token};
+
+/// A heap of keys ordered by priority, smallest first, where each key
+/// is addressed directly instead of through a `Token`.
+///
+/// Each key is in the heap at most once, which makes this a good fit
+/// for graph searches that repeatedly lower the distance of a vertex.
+#[derive(Debug)]
+pub struct IndexedHeap<K, P> {
+    heap: Heap<(P, K), ByPriority>,
+    tokens: HashMap<K, Token<(P, K)>>,
+}
+
+impl<K, P> IndexedHeap<K, P>
+    where K: Hash + Eq + Clone,
+          P: Ord,
+{
+    pub fn new() -> IndexedHeap<K, P> {
+        IndexedHeap {
+            heap: Heap::with_comparator(ByPriority),
+            tokens: HashMap::new(),
+        }
+    }
+
+    /// Adds `key` with `priority`, or lowers the priority of `key` if
+    /// it is already present and `priority` is lower than its current
+    /// one. Returns whether anything changed.
+    pub fn push_or_decrease(&mut self, key: K, priority: P) -> bool {
+        match self.tokens.entry(key) {
+            Entry::Occupied(entry) => {
+                let node = self.heap.resolve(*entry.get()).expect("Indexed token is stale");
+                let node_r = unsafe { &*node };
+
+                if priority >= node_r.value.0 { return false }
+
+                self.heap.decrease_node(node, |entry| entry.0 = priority);
+            }
+            Entry::Vacant(entry) => {
+                // The token is recorded before anything is compared, so a
+                // panicking `Ord` can't leave a key the map doesn't know.
+                let key = entry.key().clone();
+                let token = self.heap.push_unlinked((priority, key));
+                entry.insert(token);
+                self.heap.link_new(token);
+            }
+        }
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<(K, P)> {
+        // Forget the key first; if combining panics, the entry is gone
+        // from the heap too.
+        self.tokens.remove(&self.heap.peek()?.1);
+        let (priority, key) = self.heap.pop().expect("Heap emptied while peeking");
+        Some((key, priority))
+    }
+
+    /// The entry that `pop` would return next.
+    pub fn peek(&self) -> Option<(&K, &P)> {
+        self.heap.peek().map(|entry| (&entry.1, &entry.0))
+    }
+
+    pub fn priority(&self, key: &K) -> Option<&P> {
+        let &token = self.tokens.get(key)?;
+        let node = self.heap.resolve(token).expect("Indexed token is stale");
+        let node_r = unsafe { &*node };
+        Some(&node_r.value.0)
+    }
+
+    /// Removes `key` from anywhere in the heap, returning its priority.
+    pub fn remove(&mut self, key: &K) -> Option<P> {
+        let token = self.tokens.remove(key)?;
+        let node = self.heap.resolve(token).expect("Indexed token is stale");
+        let (priority, _) = self.heap.remove_node(node);
+        Some(priority)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.tokens.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.tokens.clear();
+        self.heap.clear();
+    }
+}
+
+impl<K, P> Default for IndexedHeap<K, P>
+    where K: Hash + Eq + Clone,
+          P: Ord,
+{
+    fn default() -> Self {
+        IndexedHeap::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use unwind::{self, Fragile};
+    use IndexedHeap;
+
+    /// Every key in the heap must be in the map exactly once.
+    fn check_keys(mut h: IndexedHeap<u32, Fragile<u32>>) {
+        let len = h.len();
+        assert_eq!(len, h.heap.len());
+        let mut popped = Vec::new();
+        while let Some((key, _)) = h.pop() {
+            assert!(!popped.contains(&key), "Key {} popped twice", key);
+            popped.push(key);
+        }
+        assert_eq!(len, popped.len());
+    }
+
+    fn fragile_heap() -> IndexedHeap<u32, Fragile<u32>> {
+        let mut h = IndexedHeap::new();
+        for i in 0..20 {
+            h.push_or_decrease(i, Fragile(i * 7 % 20 + 100));
+        }
+        h.pop();
+        h
+    }
+
+    #[test]
+    fn panicking_comparison_during_push_keeps_keys_unique() {
+        unwind::check(fragile_heap, |h| {
+            for i in 20..25 {
+                h.push_or_decrease(i, Fragile(i));
+            }
+        }, |mut h| {
+            for i in 20..25 {
+                h.push_or_decrease(i, Fragile(i));
+            }
+            check_keys(h);
+        });
+    }
+
+    #[test]
+    fn panicking_comparison_during_decrease_and_pop_keeps_keys_unique() {
+        unwind::check(fragile_heap, |h| {
+            for i in (0..20).step_by(3) {
+                h.push_or_decrease(i, Fragile(i));
+            }
+            while h.pop().is_some() {}
+        }, check_keys);
+    }
+
+    #[test]
+    fn keys_are_popped_by_priority() {
+        let mut h = IndexedHeap::new();
+
+        assert!(h.push_or_decrease("c", 3));
+        assert!(h.push_or_decrease("a", 1));
+        assert!(h.push_or_decrease("b", 2));
+
+        assert_eq!(Some((&"a", &1)), h.peek());
+        assert_eq!(Some(("a", 1)), h.pop());
+        assert_eq!(Some(("b", 2)), h.pop());
+        assert_eq!(Some(("c", 3)), h.pop());
+        assert_eq!(None, h.pop());
+        assert!(h.is_empty());
+    }
+
+    #[test]
+    fn only_lower_priorities_replace_the_current_one() {
+        let mut h = IndexedHeap::new();
+
+        h.push_or_decrease(42, 10);
+        h.push_or_decrease(7, 8);
+
+        assert!(!h.push_or_decrease(42, 11));
+        assert_eq!(Some(&10), h.priority(&42));
+
+        assert!(h.push_or_decrease(42, 5));
+        assert_eq!(Some(&5), h.priority(&42));
+        assert_eq!(2, h.len());
+
+        assert_eq!(Some((42, 5)), h.pop());
+        assert_eq!(Some((7, 8)), h.pop());
+    }
+
+    #[test]
+    fn popped_keys_are_forgotten() {
+        let mut h = IndexedHeap::new();
+
+        h.push_or_decrease(1, 1);
+        assert!(h.contains_key(&1));
+
+        h.pop();
+        assert!(!h.contains_key(&1));
+        assert_eq!(None, h.priority(&1));
+
+        assert!(h.push_or_decrease(1, 10));
+        assert_eq!(Some((1, 10)), h.pop());
+    }
+
+    #[test]
+    fn keys_can_be_removed_from_anywhere() {
+        let mut h = IndexedHeap::new();
+        for i in 0..20 {
+            h.push_or_decrease(i, i * 10);
+        }
+
+        assert_eq!(Some(0), h.remove(&0));
+        assert_eq!(Some(70), h.remove(&7));
+        assert_eq!(Some(190), h.remove(&19));
+        assert_eq!(None, h.remove(&7));
+        assert!(!h.contains_key(&7));
+
+        for i in (1..19).filter(|&i| i != 7) {
+            assert_eq!(Some((i, i * 10)), h.pop());
+        }
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn dijkstra_shortest_paths() {
+        let edges: &[&[(usize, u32)]] = &[
+            &[(1, 7), (2, 9), (5, 14)],
+            &[(0, 7), (2, 10), (3, 15)],
+            &[(0, 9), (1, 10), (3, 11), (5, 2)],
+            &[(1, 15), (2, 11), (4, 6)],
+            &[(3, 6), (5, 9)],
+            &[(0, 14), (2, 2), (4, 9)],
+        ];
+
+        let mut dist = vec![None; edges.len()];
+        let mut h = IndexedHeap::new();
+        h.push_or_decrease(0, 0);
+
+        while let Some((v, d)) = h.pop() {
+            dist[v] = Some(d);
+            for &(w, cost) in edges[v] {
+                if dist[w].is_none() {
+                    h.push_or_decrease(w, d + cost);
+                }
+            }
+        }
+
+        assert_eq!(vec![Some(0), Some(7), Some(9), Some(20), Some(20), Some(11)], dist);
+    }
+}
@@ -0,0 +1,434 @@
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::ptr;
+
+use {CombineStrategy, Compare, Dismantle, Heap, HeapId, Node, Token, TwoPass};
+
+/// Walks every node of a tree with an explicit stack, in no particular
+/// order.
+struct Nodes<T> {
+    stack: Vec<*mut Node<T>>,
+    remaining: usize,
+}
+
+impl<T> Nodes<T> {
+    fn new<C, S>(heap: &Heap<T, C, S>) -> Self {
+        let mut stack = Vec::new();
+        if !heap.root.is_null() {
+            stack.push(heap.root);
+        }
+
+        Nodes {
+            stack,
+            remaining: heap.len,
+        }
+    }
+}
+
+impl<T> Iterator for Nodes<T> {
+    type Item = *mut Node<T>;
+
+    fn next(&mut self) -> Option<*mut Node<T>> {
+        let node = self.stack.pop()?;
+        let node_r = unsafe { &*node };
+
+        if !node_r.next.is_null() {
+            self.stack.push(node_r.next);
+        }
+        if !node_r.first_child.is_null() {
+            self.stack.push(node_r.first_child);
+        }
+
+        self.remaining -= 1;
+        Some(node)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+/// An iterator over the values of a `Heap` in arbitrary order,
+/// returned by `iter`.
+pub struct Iter<'a, T: 'a> {
+    nodes: Nodes<T>,
+    _marker: PhantomData<&'a T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.nodes.next().map(|node| {
+            let node_r = unsafe { &*node };
+            &*node_r.value
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.nodes.size_hint()
+    }
+}
+
+impl<'a, T> ExactSizeIterator for Iter<'a, T> {}
+
+// Only shared references to values are handed out, like `&T`.
+unsafe impl<'a, T> Send for Iter<'a, T> where T: Sync {}
+unsafe impl<'a, T> Sync for Iter<'a, T> where T: Sync {}
+
+/// An iterator over the values of a `Heap` and their tokens in
+/// arbitrary order, returned by `iter_with_tokens`.
+pub struct IterWithTokens<'a, T: 'a> {
+    nodes: Nodes<T>,
+    heap: HeapId,
+    _marker: PhantomData<&'a T>,
+}
+
+impl<'a, T> Iterator for IterWithTokens<'a, T> {
+    type Item = (Token<T>, &'a T);
+
+    fn next(&mut self) -> Option<(Token<T>, &'a T)> {
+        self.nodes.next().map(|node| {
+            let node_r = unsafe { &*node };
+            let token = Token {
+                node,
+                heap: self.heap,
+                generation: node_r.generation,
+            };
+            (token, &*node_r.value)
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.nodes.size_hint()
+    }
+}
+
+impl<'a, T> ExactSizeIterator for IterWithTokens<'a, T> {}
+
+unsafe impl<'a, T> Send for IterWithTokens<'a, T> where T: Sync {}
+unsafe impl<'a, T> Sync for IterWithTokens<'a, T> where T: Sync {}
+
+impl<T, C, S> Heap<T, C, S> {
+    /// Iterates over the values in arbitrary order.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            nodes: Nodes::new(self),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Iterates over the values and a token for each in arbitrary
+    /// order.
+    pub fn iter_with_tokens(&self) -> IterWithTokens<'_, T> {
+        IterWithTokens {
+            nodes: Nodes::new(self),
+            heap: self.id,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T, C, S> IntoIterator for &'a Heap<T, C, S> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+/// An iterator that pops the values of a `Heap` in order, returned by
+/// `into_iter_sorted`.
+pub struct IntoIterSorted<T, C, S = TwoPass> {
+    heap: Heap<T, C, S>,
+}
+
+impl<T, C, S> Iterator for IntoIterSorted<T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.heap.pop()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.heap.len(), Some(self.heap.len()))
+    }
+}
+
+impl<T, C, S> ExactSizeIterator for IntoIterSorted<T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{}
+
+/// An iterator that pops the values of a `Heap` in order, returned by
+/// `drain_sorted`. Any values left when it is dropped are removed.
+pub struct DrainSorted<'a, T: 'a, C: 'a + Compare<T>, S: 'a + CombineStrategy = TwoPass> {
+    heap: &'a mut Heap<T, C, S>,
+}
+
+impl<'a, T, C, S> Iterator for DrainSorted<'a, T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.heap.pop()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.heap.len(), Some(self.heap.len()))
+    }
+}
+
+impl<'a, T, C, S> ExactSizeIterator for DrainSorted<'a, T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{}
+
+impl<'a, T, C, S> Drop for DrainSorted<'a, T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    fn drop(&mut self) {
+        self.heap.clear();
+    }
+}
+
+/// An iterator that removes the values of a `Heap` in arbitrary order,
+/// returned by `drain`. Any values left when it is dropped are
+/// removed.
+pub struct Drain<'a, T: 'a, C: 'a, S: 'a = TwoPass> {
+    // Keeps the heap borrowed, although it has no nodes left to share.
+    _heap: PhantomData<&'a mut Heap<T, C, S>>,
+    nodes: Dismantle<T>,
+    remaining: usize,
+}
+
+impl<'a, T, C, S> Iterator for Drain<'a, T, C, S> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let node = self.nodes.next()?;
+        self.remaining -= 1;
+
+        // Every token is already stale, so the node is freed rather
+        // than recycled.
+        let mut node = unsafe { Box::from_raw(node) };
+        Some(unsafe { ManuallyDrop::take(&mut node.value) })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, T, C, S> ExactSizeIterator for Drain<'a, T, C, S> {}
+
+// The remaining nodes are owned by the iterator, like `&mut Heap`.
+unsafe impl<'a, T, C, S> Send for Drain<'a, T, C, S>
+    where T: Send,
+          C: Send,
+          S: Send,
+{}
+
+unsafe impl<'a, T, C, S> Sync for Drain<'a, T, C, S>
+    where T: Sync,
+          C: Sync,
+          S: Sync,
+{}
+
+impl<'a, T, C, S> Drop for Drain<'a, T, C, S> {
+    fn drop(&mut self) {
+        // Keeps dropping the rest if one of the values panics.
+        struct DropGuard<'r, 'a: 'r, T: 'a, C: 'a, S: 'a>(&'r mut Drain<'a, T, C, S>);
+
+        impl<'r, 'a, T, C, S> Drop for DropGuard<'r, 'a, T, C, S> {
+            fn drop(&mut self) {
+                for _ in self.0.by_ref() {}
+            }
+        }
+
+        while let Some(value) = self.next() {
+            let guard = DropGuard(self);
+            drop(value);
+            mem::forget(guard);
+        }
+    }
+}
+
+impl<T, C, S> Heap<T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    /// The values in the order `pop` would return them.
+    pub fn into_sorted_vec(mut self) -> Vec<T> {
+        let mut values = Vec::with_capacity(self.len());
+        while let Some(value) = self.pop() {
+            values.push(value);
+        }
+        values
+    }
+
+    /// The values in arbitrary order. This skips combining siblings
+    /// entirely, so it is O(n).
+    pub fn into_vec(mut self) -> Vec<T> {
+        self.drain().collect()
+    }
+
+    /// Consumes the heap, lazily popping its values in order.
+    pub fn into_iter_sorted(self) -> IntoIterSorted<T, C, S> {
+        IntoIterSorted { heap: self }
+    }
+
+    /// Pops the values in order, emptying the heap even if the
+    /// iterator is not run to completion.
+    pub fn drain_sorted(&mut self) -> DrainSorted<'_, T, C, S> {
+        DrainSorted { heap: self }
+    }
+
+    /// Removes the values in arbitrary order without combining any
+    /// siblings. The heap is empty as soon as this is called, and every
+    /// token issued so far becomes stale.
+    pub fn drain(&mut self) -> Drain<'_, T, C, S> {
+        let root = self.root;
+        let remaining = self.len;
+
+        self.root = ptr::null_mut();
+        self.len = 0;
+        // The iterator frees its nodes as it goes, and may be leaked
+        // before it gets to them, so no token can be trusted after this.
+        self.invalidate_tokens();
+        unsafe { self.free_unused() };
+
+        Drain {
+            _heap: PhantomData,
+            nodes: unsafe { Dismantle::new(root) },
+            remaining,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {Heap, StaleToken};
+
+    #[test]
+    fn iterating_an_empty_heap() {
+        let h = Heap::<u8>::new();
+        assert_eq!(0, h.iter().len());
+        assert_eq!(None, h.iter().next());
+    }
+
+    #[test]
+    fn iterating_visits_every_value_once() {
+        let mut h = Heap::new();
+        for i in (0..50).rev() { h.push(i); }
+        for i in 50..100 { h.push(i); }
+        h.pop();
+
+        let mut values: Vec<_> = h.iter().cloned().collect();
+        values.sort();
+        assert_eq!((1..100).collect::<Vec<_>>(), values);
+
+        assert_eq!(99, (&h).into_iter().len());
+        assert_eq!(99, h.len());
+    }
+
+    #[test]
+    fn iterated_tokens_refer_to_their_values() {
+        let mut h = Heap::new();
+        for i in 0..10 { h.push(i * 10); }
+
+        let t = h.iter_with_tokens()
+            .find(|&(_, &v)| v == 70)
+            .map(|(t, _)| t)
+            .unwrap();
+
+        h.decrease_key(t, |v| *v = 5);
+        assert_eq!(Some(0), h.pop());
+        assert_eq!(Some(5), h.pop());
+        assert_eq!(Some(10), h.pop());
+    }
+
+    #[test]
+    fn for_loops_borrow_the_heap() {
+        let mut h = Heap::new();
+        h.push(1);
+        h.push(2);
+
+        let mut sum = 0;
+        for v in &h { sum += v; }
+        assert_eq!(3, sum);
+    }
+
+    #[test]
+    fn sorted_vec_is_in_pop_order() {
+        let mut h = Heap::new();
+        for &i in &[5, 3, 8, 1, 9, 2] { h.push(i); }
+
+        assert_eq!(vec![1, 2, 3, 5, 8, 9], h.into_sorted_vec());
+    }
+
+    #[test]
+    fn into_iter_sorted_is_lazy_and_sized() {
+        let mut h = Heap::new();
+        for i in (0..10).rev() { h.push(i); }
+
+        let mut iter = h.into_iter_sorted();
+        assert_eq!((10, Some(10)), iter.size_hint());
+        assert_eq!(Some(0), iter.next());
+        assert_eq!(Some(1), iter.next());
+        assert_eq!(8, iter.len());
+        assert_eq!((2..10).collect::<Vec<_>>(), iter.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn drain_sorted_empties_the_heap_even_when_stopped_early() {
+        let mut h = Heap::new();
+        for i in (0..10).rev() { h.push(i); }
+
+        assert_eq!(vec![0, 1, 2], h.drain_sorted().take(3).collect::<Vec<_>>());
+        assert!(h.is_empty());
+
+        h.push(1);
+        assert_eq!(Some(1), h.pop());
+    }
+
+    #[test]
+    fn drain_removes_everything_in_any_order() {
+        let mut h = Heap::new();
+        let t = h.push(0);
+        for i in 1..20 { h.push(i); }
+
+        {
+            let mut drain = h.drain();
+            assert_eq!(20, drain.len());
+            drain.next();
+            assert_eq!(19, drain.len());
+        }
+        assert!(h.is_empty());
+        assert_eq!(Err(StaleToken), h.try_decrease_key(t, |v| *v = 0));
+
+        h.push(2);
+        h.push(1);
+        let mut values = h.drain().collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(vec![1, 2], values);
+    }
+
+    #[test]
+    fn into_vec_keeps_every_value() {
+        let mut h = Heap::new();
+        for i in 0..20 { h.push(i); }
+        h.pop();
+
+        let mut values = h.into_vec();
+        values.sort();
+        assert_eq!((1..20).collect::<Vec<_>>(), values);
+    }
+}
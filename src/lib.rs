@@ -1,22 +1,117 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::mem::{self, ManuallyDrop};
 use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 
 // Implementation heavily inspired by
 // C++ Program to Implement Pairing Heap
 // http://www.sanfoundry.com/cpp-program-implement-pairing-heap/
 
-// TODO: make a max heap to match stdlib?
-
-// TODO: lifetime tied to heap?
+pub use arena::{ArenaHeap, ArenaToken};
+pub use brand::{BrandedHeap, BrandedToken};
+pub use combine::{BackToFront, CombineStrategy, Forest, FrontToBack, Multipass, TwoPass};
+pub use compare::{Compare, Max, Min};
+pub use indexed::IndexedHeap;
+pub use iter::{Drain, DrainSorted, IntoIterSorted, Iter, IterWithTokens};
+pub use max::MaxHeap;
+pub use peek::PeekMut;
+pub use priority::PriorityHeap;
+pub use rank::{RankPairingHeap, RankToken};
+pub use traits::TokenHeap;
+
+mod arena;
+mod brand;
+mod build;
+mod combine;
+mod compare;
+mod indexed;
+mod iter;
+mod max;
+mod peek;
+mod priority;
+mod rank;
+mod slab;
+mod traits;
+#[cfg(test)]
+mod unwind;
+
+/// A handle to a value in a `Heap`, returned by `push`.
+///
+/// The token remembers which heap issued it and which generation of
+/// the node it refers to, so using it after the value has been popped
+/// or with a different heap is detected instead of touching freed
+/// memory. See `Heap::scope` for tokens that the compiler ties to
+/// their heap.
+///
+/// Tokens are `Send` and `Sync` whatever `T` is. A token on its own
+/// gives no access to the value; that always goes through the heap,
+/// which has to be reachable from the same thread anyway.
 #[derive(Debug)]
-pub struct Token<T>(*mut Node<T>);
+pub struct Token<T> {
+    node: *mut Node<T>,
+    heap: HeapId,
+    generation: usize,
+}
 
 impl<T> Copy for Token<T> {}
 impl<T> Clone for Token<T> {
     fn clone(&self) -> Self { *self }
 }
 
+// The pointer is only ever dereferenced by a heap that has checked the
+// token belongs to it.
+unsafe impl<T> Send for Token<T> {}
+unsafe impl<T> Sync for Token<T> {}
+
+/// The error returned when a `Token` no longer refers to a value in
+/// the heap it is used with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StaleToken;
+
+impl fmt::Display for StaleToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("token does not refer to a value in this heap")
+    }
+}
+
+impl Error for StaleToken {}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct HeapId(u64);
+
+impl HeapId {
+    /// A new ID, never handed out before. IDs are not reused, as a stale
+    /// token matching a live heap's ID would reach freed memory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if every 64-bit ID has been used, like `ThreadId` does.
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+
+        let mut current = NEXT.load(AtomicOrdering::Relaxed);
+        loop {
+            let next = current.checked_add(1).expect("Ran out of heap IDs");
+            match NEXT.compare_exchange_weak(current, next, AtomicOrdering::Relaxed, AtomicOrdering::Relaxed) {
+                Ok(_) => return HeapId(current),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
 struct Node<T> {
-    value: T,
+    value: ManuallyDrop<T>,
+    // Bumped every time the node is recycled, invalidating old tokens.
+    // It may wrap, letting a very old token reach whatever value now
+    // lives in the node. That is a logic error, not a memory one: a
+    // token is only checked against nodes its heap still owns.
+    generation: usize,
+    // When the value was pushed, which breaks ties in stable mode.
+    seq: u64,
     first_child: *mut Node<T>,
     prev: *mut Node<T>,
     next: *mut Node<T>,
@@ -25,7 +120,9 @@ struct Node<T> {
 impl<T> Node<T> {
     fn new(value: T) -> Self {
         Node {
-            value: value,
+            value: ManuallyDrop::new(value),
+            generation: 0,
+            seq: 0,
             first_child: ptr::null_mut(),
             prev: ptr::null_mut(),
             next: ptr::null_mut(),
@@ -33,298 +130,2011 @@ impl<T> Node<T> {
     }
 }
 
-pub struct Heap<T> {
+/// Popped nodes are kept on a free list and reused rather than freed.
+/// This is what allows a `Token` to safely check the generation of the
+/// node it points at. `clear` and `drain` make every token stale, so
+/// they free the nodes instead, giving the memory back.
+///
+/// Values are ordered by the comparator `C`; the default of `Min` pops
+/// the smallest value first. The children of a popped node are combined
+/// by the strategy `S`, which defaults to the classic `TwoPass`.
+///
+/// A heap owns all of its nodes, so like a `Vec` it is `Send` when its
+/// values and comparator are, and `Sync` when they are `Sync`:
+///
+/// ```compile_fail
+/// use std::rc::Rc;
+/// use pairing_heap::Heap;
+///
+/// fn assert_send<T: Send>(_: T) {}
+/// assert_send(Heap::<Rc<u8>>::new());
+/// ```
+///
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use pairing_heap::Heap;
+///
+/// fn assert_sync<T: Sync>(_: &T) {}
+/// let heap = Heap::with_comparator(|a: &Cell<u8>, b: &Cell<u8>| a.get().cmp(&b.get()));
+/// assert_sync(&heap);
+/// ```
+pub struct Heap<T, C = Min, S = TwoPass> {
+    id: HeapId,
+    // The IDs of heaps that were appended to this one while they held
+    // values; their tokens refer to nodes this heap now owns.
+    adopted: HashSet<HeapId>,
+    // Any trees buffered by lazy insertion follow the root as its `next`
+    // siblings. The root always comes before all of them.
     root: *mut Node<T>,
-    combine_siblings: CombineSiblings<T>,
+    len: usize,
+    lazy_insert: bool,
+    stable: bool,
+    // The sequence number for the next value pushed.
+    next_seq: u64,
+    free: *mut Node<T>,
+    free_tail: *mut Node<T>,
+    // Scratch space for the trees being combined, kept to reuse its
+    // allocation.
+    tree_array: Vec<*mut Node<T>>,
+    compare: C,
+    strategy: S,
 }
 
 impl<T> Heap<T>
     where T: Ord,
 {
     pub fn new() -> Heap<T> {
-        Heap {
-            root: ptr::null_mut(),
-            combine_siblings: CombineSiblings::new(),
-        }
+        Heap::with_comparator(Min)
+    }
+}
+
+impl<T, C> Heap<T, C>
+    where C: Compare<T>,
+{
+    /// Creates a heap ordered by `compare`, which can be a closure
+    /// `Fn(&T, &T) -> Ordering` or any other `Compare` implementation.
+    pub fn with_comparator(compare: C) -> Heap<T, C> {
+        Heap::empty(compare, TwoPass)
+    }
+}
+
+impl<T, C, S> Heap<T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    /// Creates a heap ordered by `compare` that combines siblings with
+    /// `strategy`.
+    pub fn with_strategy(compare: C, strategy: S) -> Heap<T, C, S> {
+        Heap::empty(compare, strategy)
     }
 
     pub fn push(&mut self, value: T) -> Token<T> {
-        let node = Box::into_raw(Box::new(Node::new(value)));
+        if self.lazy_insert && !self.root.is_null() {
+            // Compare before allocating, so that if it panics the heap
+            // is left as it was. The new value is the latest pushed, so
+            // in stable mode it never wins a tie.
+            let root_r = unsafe { &*self.root };
+            let first = self.compare.compare(&value, &root_r.value) == Ordering::Less;
+
+            let node = self.allocate(value);
+            self.len += 1;
+            unsafe { self.buffer(node, first) };
+
+            return self.token(node);
+        }
+
+        let node = self.allocate(value);
+        self.len += 1;
+        self.link_with_root(node);
+
+        self.token(node)
+    }
 
+    /// Allocates a node for `value` without comparing it to anything,
+    /// so its token can be recorded before `link_new` is called.
+    fn push_unlinked(&mut self, value: T) -> Token<T> {
+        let node = self.allocate(value);
+        self.len += 1;
+        self.token(node)
+    }
+
+    /// Links a node from `push_unlinked` with the root.
+    fn link_new(&mut self, token: Token<T>) {
+        self.link_with_root(token.node);
+    }
+
+    /// Moves every value of `other` into this heap, leaving `other`
+    /// empty. Tokens issued by `other` can be used with this heap
+    /// afterwards. Values are ordered with this heap's comparator.
+    ///
+    /// Linking the two trees is O(1). On top of that, any values `other`
+    /// has buffered through lazy insertion are folded into its tree
+    /// first, and the IDs of heaps appended to either one before are
+    /// merged, which is linear in the smaller number of them.
+    pub fn append(&mut self, other: &mut Heap<T, C, S>) {
+        other.fold_buffer();
+
+        // Compare before touching either heap, in case it panics.
         self.root = if self.root.is_null() {
-            node
+            other.root
+        } else if other.root.is_null() {
+            self.root
         } else {
-            compare_and_link(self.root, node)
+            link_with_root(&self.compare, self.stable, self.root, other.root)
         };
+        self.next_seq = self.next_seq.max(other.next_seq);
+
+        // Take over the free nodes too, as stale tokens from `other`
+        // may still point at them.
+        if let Some(tail_r) = unsafe { into_mut(self.free_tail) } {
+            tail_r.next = other.free;
+        } else {
+            self.free = other.free;
+        }
+        if !other.free_tail.is_null() {
+            self.free_tail = other.free_tail;
+        }
 
-        Token(node)
+        // Every token of a heap without values is already stale, so
+        // there is nothing to adopt from it.
+        if other.len > 0 {
+            if other.adopted.len() > self.adopted.len() {
+                mem::swap(&mut self.adopted, &mut other.adopted);
+            }
+            self.adopted.insert(other.id);
+            self.adopted.extend(other.adopted.drain());
+        }
+        self.len += other.len;
+
+        other.id = HeapId::new();
+        other.adopted.clear();
+        other.root = ptr::null_mut();
+        other.len = 0;
+        other.free = ptr::null_mut();
+        other.free_tail = ptr::null_mut();
     }
 
-    pub fn pop(&mut self) -> Option<T> {
-        if self.root.is_null() { return None }
+    /// Combines two heaps into one. See `append` for the cost.
+    pub fn meld(mut self, mut other: Heap<T, C, S>) -> Heap<T, C, S> {
+        self.append(&mut other);
+        self
+    }
 
-        let root = unsafe { Box::from_raw(self.root) };
+    /// Drops every value in the heap. Tokens for them become stale.
+    pub fn clear(&mut self) {
+        drop(self.drain());
+    }
 
-        self.root = if root.first_child.is_null() {
-            ptr::null_mut()
-        } else {
-            self.combine_siblings.combine_siblings(root.first_child)
-        };
+    /// Keeps only the values for which `f` returns true. The whole tree
+    /// is walked once and the surviving nodes are linked back together
+    /// afterwards, so this is O(n). Tokens for kept values stay valid.
+    pub fn retain<F>(&mut self, mut f: F)
+        where F: FnMut(&T) -> bool,
+    {
+        self.retain_mut(|value| f(value))
+    }
 
-        Some(root.value)
+    /// Turns stable mode on or off. When on, values that compare equal
+    /// are popped in the order they were pushed, at the cost of an
+    /// extra comparison of sequence numbers on every tie. A value keeps
+    /// its place among equals when its key is changed.
+    ///
+    /// Turning it on for a heap that already holds values relinks all
+    /// of them, which is O(n).
+    pub fn set_stable(&mut self, stable: bool) {
+        let relink = stable && !self.stable;
+        self.stable = stable;
+
+        // Equal values may have been linked in any order so far.
+        if relink {
+            self.retain_mut(|_| true);
+        }
     }
 
-    /// Do not increase the key!
-    pub fn decrease_key<F>(&mut self, token: Token<T>, f: F)
-        where F: FnOnce(&mut T),
+    /// Like `retain`, but `f` may also change the values it keeps.
+    pub fn retain_mut<F>(&mut self, mut f: F)
+        where F: FnMut(&mut T) -> bool,
     {
-        let node = token.0;
-        let node_r = unsafe { &mut *node };
+        let root = self.root;
+        self.root = ptr::null_mut();
+
+        // Every node is detached before `f` sees it, so if `f` panics
+        // the kept and unvisited nodes are all hung back on the root.
+        let mut detached = Detached::new(self);
+        detached.rest = unsafe { Dismantle::new(root) };
+
+        while let Some(node) = detached.rest.next() {
+            detached.push(node);
+
+            let node_r = unsafe { &mut *node };
+            if !f(&mut node_r.value) {
+                detached.pop();
+                detached.heap.len -= 1;
+                drop(unsafe { detached.heap.recycle(node) });
+            }
+        }
 
-        // Apply the change that decreases the key
-        f(&mut node_r.value);
+        detached.combine();
+        detached.link_with_root();
+    }
 
-        if node == self.root { return }
+    pub fn pop(&mut self) -> Option<T> {
+        if self.root.is_null() { return None }
+
+        let root = self.root;
+        Some(self.remove_node(root))
+    }
 
-        if let Some(p_next_r) = unsafe { into_mut(node_r.next) } {
-            p_next_r.prev = node_r.prev;
+    /// Do not increase the key! Keys are compared using the heap's
+    /// comparator, so with `Max` the value must not get smaller.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn decrease_key<F>(&mut self, token: Token<T>, f: F)
+        where F: FnOnce(&mut T),
+    {
+        if let Err(e) = self.try_decrease_key(token, f) {
+            panic!("Unable to decrease key: {}", e);
         }
+    }
 
-        let node_prev_r = unsafe { &mut *node_r.prev };
+    /// Like `decrease_key`, but reports a stale or foreign token
+    /// instead of panicking. The closure is not called in that case.
+    pub fn try_decrease_key<F>(&mut self, token: Token<T>, f: F) -> Result<(), StaleToken>
+        where F: FnOnce(&mut T),
+    {
+        let node = self.resolve(token)?;
+        self.decrease_node(node, f);
+        Ok(())
+    }
 
-        if node_prev_r.first_child == node {
-            node_prev_r.first_child = node_r.next;
-        } else {
-            node_prev_r.next = node_r.next;
+    /// Removes a value from anywhere in the heap. Its children are
+    /// combined and melded back in, the same as `pop` does for the
+    /// root, so this is amortized O(log n).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn remove(&mut self, token: Token<T>) -> T {
+        match self.try_remove(token) {
+            Ok(value) => value,
+            Err(e) => panic!("Unable to remove: {}", e),
         }
+    }
 
-        node_r.next = ptr::null_mut();;
-        self.root = compare_and_link(self.root, node);
+    /// Like `remove`, but reports a stale or foreign token instead of
+    /// panicking.
+    pub fn try_remove(&mut self, token: Token<T>) -> Result<T, StaleToken> {
+        let node = self.resolve(token)?;
+        Ok(self.remove_node(node))
     }
-}
 
-fn compare_and_link<T>(first: *mut Node<T>, second: *mut Node<T>) -> *mut Node<T>
-    where T: Ord
-{
-    if second.is_null() { return first }
+    /// Moves a value towards the back of the heap. Do not decrease the
+    /// key! The node is cut out and its children are combined and
+    /// melded back in separately, so this is amortized O(log n).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn increase_key<F>(&mut self, token: Token<T>, f: F)
+        where F: FnOnce(&mut T),
+    {
+        if let Err(e) = self.try_increase_key(token, f) {
+            panic!("Unable to increase key: {}", e);
+        }
+    }
 
-    let first_r = unsafe { &mut *first };
-    let second_r = unsafe { &mut *second };
+    /// Like `increase_key`, but reports a stale or foreign token
+    /// instead of panicking. The closure is not called in that case.
+    pub fn try_increase_key<F>(&mut self, token: Token<T>, f: F) -> Result<(), StaleToken>
+        where F: FnOnce(&mut T),
+    {
+        let node = self.resolve(token)?;
+        self.increase_node(node, f);
+        Ok(())
+    }
 
-    if second_r.value < first_r.value {
-        second_r.prev = first_r.prev;
-        first_r.prev = second;
-        first_r.next = second_r.first_child;
-        if let Some(first_next_r) = unsafe { into_mut(first_r.next) } {
-            first_next_r.prev = first;
+    /// Changes a value in either direction. If the node is still in
+    /// order with its children, it is handled like `decrease_key`,
+    /// otherwise like `increase_key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn update_key<F>(&mut self, token: Token<T>, f: F)
+        where F: FnOnce(&mut T),
+    {
+        if let Err(e) = self.try_update_key(token, f) {
+            panic!("Unable to update key: {}", e);
         }
-        second_r.first_child = first;
-        second
-    } else {
-        second_r.prev = first;
-        first_r.next = second_r.next;
-        if let Some(first_next_r) = unsafe { into_mut(first_r.next) } {
-            first_next_r.prev = first;
+    }
+
+    /// Like `update_key`, but reports a stale or foreign token instead
+    /// of panicking. The closure is not called in that case.
+    pub fn try_update_key<F>(&mut self, token: Token<T>, f: F) -> Result<(), StaleToken>
+        where F: FnOnce(&mut T),
+    {
+        let node = self.resolve(token)?;
+        let node_r = unsafe { &mut *node };
+
+        // The node stays linked while user code runs, so a panic in `f`
+        // or the comparator can only leave it out of order.
+        f(&mut node_r.value);
+
+        // The root may now come after trees buffered by lazy insertion.
+        // Folding them in is paid for by their insertion, while checking
+        // them all on every update would not be.
+        if node == self.root {
+            self.fold_buffer();
         }
-        second_r.next = first_r.first_child;
-        if let Some(second_next_r) = unsafe { into_mut(second_r.next) } {
-            second_next_r.prev = second;
+
+        if self.children_in_order(node) {
+            self.move_to_root(node);
+        } else {
+            self.move_children_to_root(node);
         }
-        first_r.first_child = second;
-        first
+        Ok(())
     }
-}
 
-unsafe fn into_mut<'a, T>(v: *mut T) -> Option<&'a mut T> {
-    if v.is_null() {
-        None
-    } else {
-        Some(&mut *v)
+    fn decrease_node<F>(&mut self, node: *mut Node<T>, f: F)
+        where F: FnOnce(&mut T),
+    {
+        let node_r = unsafe { &mut *node };
+
+        // Apply the change that decreases the key
+        f(&mut node_r.value);
+
+        debug_assert!(self.children_in_order(node), "Value moved towards the back of the heap");
+
+        self.move_to_root(node);
     }
-}
 
-struct CombineSiblings<T> {
-    tree_array: Vec<*mut Node<T>>,
-}
+    fn increase_node<F>(&mut self, node: *mut Node<T>, f: F)
+        where F: FnOnce(&mut T),
+    {
+        let node_r = unsafe { &mut *node };
 
-impl<T> CombineSiblings<T>
-    where T: Ord
-{
-    fn new() -> Self {
-        CombineSiblings {
-            tree_array: Vec::with_capacity(5),
-        }
+        // Apply the change that increases the key
+        f(&mut node_r.value);
+
+        self.move_children_to_root(node);
     }
 
-    fn combine_siblings(&mut self, mut first_sibling: *mut Node<T>) -> *mut Node<T> {
-        let first_sibling_r = unsafe { &mut *first_sibling };
+    /// Whether none of the node's children should come before it.
+    fn children_in_order(&self, node: *mut Node<T>) -> bool {
+        let node_r = unsafe { &*node };
+        let mut child = node_r.first_child;
 
-        if first_sibling_r.next.is_null() {
-            return first_sibling;
+        while let Some(child_r) = unsafe { into_mut(child) } {
+            if comes_before(&self.compare, self.stable, child, node) {
+                return false;
+            }
+            child = child_r.next;
         }
+        true
+    }
 
-        self.tree_array.clear();
+    /// Restores heap order after a node has moved towards the front.
+    /// Its subtree is still in order, so it is cut out as a whole and
+    /// linked with the root.
+    fn move_to_root(&mut self, node: *mut Node<T>) {
+        if node == self.root { return }
 
-        while let Some(first_sibling_r) = unsafe { into_mut(first_sibling) } {
-            self.tree_array.push(first_sibling);
-            let first_sibling_prev_r = unsafe { &mut *first_sibling_r.prev };
-            first_sibling_prev_r.next = ptr::null_mut();
-            first_sibling = first_sibling_r.next;
-        }
+        unsafe { cut(node) };
+        self.link_with_root(node);
+    }
 
-        // Pad with a NULL to ensure all siblings are in an even amount
-        self.tree_array.push(ptr::null_mut());
-        let logical_length = self.tree_array.len() / 2 * 2;
-        let tree_array = &mut self.tree_array[0..logical_length];
+    /// Restores heap order after a node has moved towards the back. Its
+    /// children may now belong before it, so they are combined into
+    /// their own tree and both are linked with the root.
+    fn move_children_to_root(&mut self, node: *mut Node<T>) {
+        let buffered = self.detach(node);
 
-        // Walk forward in pairs, leaving the result in the {0,2,4,...}
-        // indexes. Then walk backward across the {[2,4], [0,2]} results,
-        // leaving the result in the first index. The final result will be
-        // in index 0.
+        let node_r = unsafe { &mut *node };
+        let children = node_r.first_child;
+        node_r.first_child = ptr::null_mut();
+
+        let mut detached = Detached::new(self);
+        detached.push_siblings(buffered);
+        detached.combine_buffered();
+        detached.push_siblings(children);
+        detached.push(node);
+        detached.combine();
+        detached.link_with_root();
+    }
 
-        // 0     1  2   3  4  5
-        // ----------------
-        // 1     2  3   4  5
-        // 1     2  3   4  5  N -- Padded with NULL
-        // 12    X  3   4  5  N
-        // 12    X  34  X  5  N
-        // 12    X  34  X  5  X -- forward pass done
-        // 12    X  345 X  X  X
-        // 12345 X  X   X  X  X -- backward pass done
+    /// Unlinks any node from the heap, melding its children back in.
+    fn remove_node(&mut self, node: *mut Node<T>) -> T {
+        let buffered = self.detach(node);
+        let children = unsafe { (*node).first_child };
 
-        for chunk in tree_array.chunks_mut(2) {
-            chunk[0] = compare_and_link(chunk[0], chunk[1]);
-        }
+        // The value is taken out before any comparison, so if one panics
+        // it is dropped and the node is not left half removed.
+        self.len -= 1;
+        let value = unsafe { self.recycle(node) };
 
-        if logical_length >= 4 {
-            let mut end_idx = logical_length - 2;
+        let mut detached = Detached::new(self);
+        detached.push_siblings(buffered);
+        detached.combine_buffered();
+        detached.push_siblings(children);
+        detached.combine();
+        detached.link_with_root();
 
-            while end_idx >= 2  {
-                let start_idx = end_idx - 2;
-                tree_array[start_idx] = compare_and_link(tree_array[start_idx], tree_array[end_idx]);
-                end_idx -= 2;
-            }
-        }
+        value
+    }
+
+    /// Links the trees buffered by lazy insertion into the root.
+    fn fold_buffer(&mut self) {
+        let root_r = match unsafe { into_mut(self.root) } {
+            Some(root_r) => root_r,
+            None => return,
+        };
+        let buffered = root_r.next;
+        root_r.next = ptr::null_mut();
+
+        let mut detached = Detached::new(self);
+        detached.push_siblings(buffered);
+        detached.combine_buffered();
+        detached.link_with_root();
+    }
 
-        tree_array[0]
+    /// Links a single detached tree with the root.
+    fn link_with_root(&mut self, tree: *mut Node<T>) {
+        let mut detached = Detached::new(self);
+        detached.push(tree);
+        detached.link_with_root();
     }
 }
 
-#[cfg(test)]
-mod test {
-    use Heap;
+impl<T, C, S> Heap<T, C, S> {
+    fn empty(compare: C, strategy: S) -> Heap<T, C, S> {
+        Heap {
+            lazy_insert: false,
+            stable: false,
+            next_seq: 0,
+            id: HeapId::new(),
+            adopted: HashSet::new(),
+            root: ptr::null_mut(),
+            len: 0,
+            free: ptr::null_mut(),
+            free_tail: ptr::null_mut(),
+            tree_array: Vec::with_capacity(5),
+            compare,
+            strategy,
+        }
+    }
 
-    #[test]
-    fn empty_heap_pops_none() {
-        let mut h = Heap::<u8>::new();
-        assert_eq!(None, h.pop());
+    /// The number of values in the heap.
+    pub fn len(&self) -> usize {
+        self.len
     }
 
-    #[test]
-    fn heap_with_one_value() {
-        let mut h = Heap::new();
-        h.push(1);
-        assert_eq!(Some(1), h.pop());
-        assert_eq!(None, h.pop());
+    /// Turns lazy insertion on or off. When on, `push` only compares
+    /// the new value with the front of the heap and puts it in a
+    /// buffer. The buffer is combined with `Multipass` and folded in
+    /// by the next `pop`, which together with `TwoPass` is the
+    /// auxiliary two-pass pairing heap. This suits workloads that push
+    /// far more often than they pop.
+    ///
+    /// `peek` stays O(1), and tokens for buffered values work as usual.
+    pub fn set_lazy_insert(&mut self, lazy: bool) {
+        self.lazy_insert = lazy;
     }
 
-    #[test]
-    fn multiple_values_inserted_in_order_returns_them_in_order() {
-        let mut h = Heap::new();
-        h.push(1);
-        h.push(2);
-        assert_eq!(Some(1), h.pop());
-        assert_eq!(Some(2), h.pop());
-        assert_eq!(None, h.pop());
+    /// Whether lazy insertion is on. See `set_lazy_insert`.
+    pub fn lazy_insert(&self) -> bool {
+        self.lazy_insert
     }
 
-    #[test]
-    fn multiple_values_inserted_out_of_order_returns_them_in_order() {
-        let mut h = Heap::new();
-        h.push(2);
-        h.push(1);
-        assert_eq!(Some(1), h.pop());
-        assert_eq!(Some(2), h.pop());
-        assert_eq!(None, h.pop());
+    /// Whether equal values are popped in the order they were pushed.
+    /// See `set_stable`.
+    pub fn stable(&self) -> bool {
+        self.stable
     }
 
-    #[test]
-    fn duplicate_values_are_kept() {
-        let mut h = Heap::new();
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
 
-        for i in 0..5 { h.push(i); }
-        for i in 0..5 { h.push(i); }
+    /// The value that `pop` would return next.
+    pub fn peek(&self) -> Option<&T> {
+        unsafe { into_mut(self.root) }.map(|root_r| &*root_r.value)
+    }
 
-        for i in 0..5 {
-            assert_eq!(Some(i), h.pop());
-            assert_eq!(Some(i), h.pop());
+    /// The current value for a token.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn get(&self, token: Token<T>) -> &T {
+        match self.try_get(token) {
+            Some(value) => value,
+            None => panic!("Unable to get value: {}", StaleToken),
         }
-        assert_eq!(None, h.pop());
     }
 
-    #[test]
-    fn interleaved_push_and_pop() {
-        let mut h = Heap::new();
-
-        h.push(0);
-        assert_eq!(Some(0), h.pop());
-
-        h.push(1);
-        h.push(1);
-        assert_eq!(Some(1), h.pop());
+    /// Like `get`, but returns `None` for a stale or foreign token.
+    pub fn try_get(&self, token: Token<T>) -> Option<&T> {
+        let node = self.resolve(token).ok()?;
+        let node_r = unsafe { &*node };
+        Some(&node_r.value)
+    }
 
-        h.push(2);
-        assert_eq!(Some(1), h.pop());
-        assert_eq!(Some(2), h.pop());
-        assert_eq!(None, h.pop());
+    /// Whether the token's value is still in this heap.
+    pub fn contains(&self, token: Token<T>) -> bool {
+        self.resolve(token).is_ok()
     }
 
-    #[test]
-    fn many_values_inserted_returns_them_in_order() {
-        let count = 123;
+    /// Whether the token's value is the one `pop` would return next.
+    pub fn is_root(&self, token: Token<T>) -> bool {
+        self.resolve(token) == Ok(self.root)
+    }
 
-        let mut h = Heap::new();
-        for i in 0..count {
-            h.push(i);
+    /// Adds a detached node to the buffer that follows the root as its
+    /// `next` siblings. If it comes `first`, it takes over as the root
+    /// and the old root joins the buffer instead.
+    unsafe fn buffer(&mut self, node: *mut Node<T>, first: bool) {
+        let node_r = &mut *node;
+        let root_r = &mut *self.root;
+
+        if first {
+            node_r.next = self.root;
+            root_r.prev = node;
+            self.root = node;
+        } else {
+            node_r.prev = self.root;
+            node_r.next = root_r.next;
+            if let Some(next_r) = into_mut(node_r.next) {
+                next_r.prev = node;
+            }
+            root_r.next = node;
         }
+    }
 
-        for i in 0..count {
-            assert_eq!(Some(i), h.pop());
+    /// Unlinks a node from the tree. If it was the root, the trees
+    /// buffered by lazy insertion are unlinked too and returned.
+    fn detach(&mut self, node: *mut Node<T>) -> *mut Node<T> {
+        if node == self.root {
+            self.root = ptr::null_mut();
+            let node_r = unsafe { &mut *node };
+            let buffered = node_r.next;
+            node_r.next = ptr::null_mut();
+            buffered
+        } else {
+            unsafe { cut(node) };
+            ptr::null_mut()
         }
-        assert_eq!(None, h.pop());
     }
 
-    #[test]
-    fn decreasing_a_key_brings_it_to_the_front() {
-        let mut h = Heap::new();
+    fn token(&self, node: *mut Node<T>) -> Token<T> {
+        Token {
+            node,
+            heap: self.id,
+            generation: unsafe { (*node).generation },
+        }
+    }
 
-        h.push(10);
-        let t2 = h.push(20);
+    /// Makes every token issued so far stale, without touching the
+    /// nodes. Only safe to rely on once no node is left in the tree.
+    fn invalidate_tokens(&mut self) {
+        self.id = HeapId::new();
+        self.adopted.clear();
+    }
 
-        h.decrease_key(t2, |v| *v = 5);
+    /// Finds the node for a token, if it is still in this heap.
+    fn resolve(&self, token: Token<T>) -> Result<*mut Node<T>, StaleToken> {
+        if token.heap != self.id && !self.adopted.contains(&token.heap) {
+            return Err(StaleToken);
+        }
 
-        assert_eq!(Some(5), h.pop());
-        assert_eq!(Some(10), h.pop());
-        assert_eq!(None, h.pop());
+        // The heap ID matches, so the node was allocated by this heap
+        // since it last freed any nodes, and is still allocated, even if
+        // it has since been recycled.
+        unsafe { self.resolve_generation(token) }
     }
 
-    #[test]
-    fn decreasing_a_key_of_many_brings_it_to_the_front() {
-        let mut h = Heap::new();
+    /// Like `resolve`, but trusts that the token came from this heap.
+    unsafe fn resolve_generation(&self, token: Token<T>) -> Result<*mut Node<T>, StaleToken> {
+        let generation = (*token.node).generation;
+        if generation != token.generation { return Err(StaleToken) }
 
-        let mut t = h.push(10);
-        for i in 11..100 {
-            t = h.push(i);
-        }
+        Ok(token.node)
+    }
 
-        h.decrease_key(t, |v| *v = 1);
+    fn allocate(&mut self, value: T) -> *mut Node<T> {
+        let node = match unsafe { into_mut(self.free) } {
+            Some(free_r) => {
+                let node = self.free;
+                self.free = free_r.next;
+                if self.free.is_null() {
+                    self.free_tail = ptr::null_mut();
+                }
+                free_r.value = ManuallyDrop::new(value);
+                free_r.next = ptr::null_mut();
+                node
+            }
+            None => Box::into_raw(Box::new(Node::new(value))),
+        };
 
-        assert_eq!(Some(1), h.pop());
+        unsafe { (*node).seq = self.next_seq };
+        self.next_seq += 1;
+        node
+    }
 
-        for i in 10..99 {
-            assert_eq!(Some(i), h.pop());
+    /// Frees every node on the free list. Only sound once no token can
+    /// reach them, as after `invalidate_tokens`.
+    unsafe fn free_unused(&mut self) {
+        drop(FreeNodes {
+            nodes: Dismantle::new(self.free),
+            has_values: false,
+        });
+        self.free = ptr::null_mut();
+        self.free_tail = ptr::null_mut();
+    }
+
+    /// Moves the value out of a node that has been unlinked from the
+    /// tree and puts the node on the free list.
+    unsafe fn recycle(&mut self, node: *mut Node<T>) -> T {
+        let node_r = &mut *node;
+        let value = ManuallyDrop::take(&mut node_r.value);
+
+        node_r.generation = node_r.generation.wrapping_add(1);
+        node_r.first_child = ptr::null_mut();
+        node_r.prev = ptr::null_mut();
+        node_r.next = self.free;
+        if self.free.is_null() {
+            self.free_tail = node;
         }
+        self.free = node;
 
-        assert_eq!(None, h.pop());
+        value
+    }
+}
+
+// The nodes are uniquely owned by the heap, and shared access to the
+// heap only ever hands out shared references to values.
+unsafe impl<T, C, S> Send for Heap<T, C, S>
+    where T: Send,
+          C: Send,
+          S: Send,
+{}
+
+unsafe impl<T, C, S> Sync for Heap<T, C, S>
+    where T: Sync,
+          C: Sync,
+          S: Sync,
+{}
+
+impl<T, C, S> Drop for Heap<T, C, S> {
+    fn drop(&mut self) {
+        let free = FreeNodes {
+            nodes: unsafe { Dismantle::new(self.free) },
+            has_values: false,
+        };
+        let mut tree = FreeNodes {
+            nodes: unsafe { Dismantle::new(self.root) },
+            has_values: true,
+        };
+        tree.free_all();
+        drop(free);
+        self.root = ptr::null_mut();
+        self.free = ptr::null_mut();
+        self.free_tail = ptr::null_mut();
+    }
+}
+
+/// Frees every node it yields, dropping the value first if the nodes
+/// came from the tree rather than the free list. If a destructor
+/// panics, dropping this frees the remaining nodes.
+struct FreeNodes<T> {
+    nodes: Dismantle<T>,
+    has_values: bool,
+}
+
+impl<T> FreeNodes<T> {
+    fn free_all(&mut self) {
+        for node in &mut self.nodes {
+            let mut node = unsafe { Box::from_raw(node) };
+            if self.has_values {
+                unsafe { ManuallyDrop::drop(&mut node.value) };
+            }
+        }
+    }
+}
+
+impl<T> Drop for FreeNodes<T> {
+    fn drop(&mut self) {
+        self.free_all();
+    }
+}
+
+/// Trees that have been taken out of the heap and not yet linked back
+/// in. Linking them calls the comparator, which may panic. Each tree
+/// stays here until it has been linked, and dropping this hangs any
+/// that are left below the root without comparing them. The heap may
+/// then be out of order, but it is structurally sound and has lost no
+/// nodes.
+struct Detached<'a, T: 'a, C: 'a, S: 'a> {
+    heap: &'a mut Heap<T, C, S>,
+    // The trees before this index have already been combined.
+    combined: usize,
+    // Nodes of a dismantled tree that have not been pushed yet.
+    rest: Dismantle<T>,
+}
+
+impl<'a, T, C, S> Detached<'a, T, C, S> {
+    fn new(heap: &'a mut Heap<T, C, S>) -> Self {
+        debug_assert!(heap.tree_array.is_empty());
+
+        Detached {
+            heap,
+            combined: 0,
+            rest: unsafe { Dismantle::new(ptr::null_mut()) },
+        }
+    }
+
+    /// Adds a tree that has been unlinked from its parent and siblings.
+    fn push(&mut self, tree: *mut Node<T>) {
+        let tree_r = unsafe { &mut *tree };
+        tree_r.prev = ptr::null_mut();
+        tree_r.next = ptr::null_mut();
+
+        self.heap.tree_array.push(tree);
+    }
+
+    /// Adds every tree in a list of siblings.
+    fn push_siblings(&mut self, mut sibling: *mut Node<T>) {
+        while let Some(sibling_r) = unsafe { into_mut(sibling) } {
+            let next = sibling_r.next;
+            self.push(sibling);
+            sibling = next;
+        }
+    }
+
+    /// Takes back the tree that was pushed last.
+    fn pop(&mut self) {
+        self.heap.tree_array.pop();
+    }
+}
+
+impl<'a, T, C, S> Detached<'a, T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    /// Combines the trees pushed since the last call into one, using
+    /// the heap's strategy.
+    fn combine(&mut self) {
+        let heap = &mut *self.heap;
+        let trees = &mut heap.tree_array[self.combined..];
+        heap.strategy.combine(&mut Forest::new(trees, &heap.compare, heap.stable));
+        self.combined = heap.tree_array.len();
+    }
+
+    /// Like `combine`, but always using `Multipass`, for the trees
+    /// buffered by lazy insertion.
+    fn combine_buffered(&mut self) {
+        let heap = &mut *self.heap;
+        let trees = &mut heap.tree_array[self.combined..];
+        Multipass.combine(&mut Forest::new(trees, &heap.compare, heap.stable));
+        self.combined = heap.tree_array.len();
+    }
+
+    fn link_with_root(self) {
+        let heap = &mut *self.heap;
+        let tree_array = &mut heap.tree_array;
+
+        for tree in tree_array.iter_mut() {
+            if tree.is_null() { continue }
+
+            heap.root = if heap.root.is_null() {
+                *tree
+            } else {
+                link_with_root(&heap.compare, heap.stable, heap.root, *tree)
+            };
+            *tree = ptr::null_mut();
+        }
+        tree_array.clear();
+    }
+
+    /// Hangs every tree below whichever one comes first, the root
+    /// included, without combining them. That is left to the next
+    /// `pop`.
+    fn link_as_children(self) {
+        let heap = &mut *self.heap;
+
+        // All the comparing happens before any link changes, so if it
+        // panics `drop` still has every tree.
+        let mut first = heap.root;
+        for &tree in &heap.tree_array {
+            if first.is_null() || comes_before(&heap.compare, heap.stable, tree, first) {
+                first = tree;
+            }
+        }
+
+        if first != heap.root {
+            if let Some(root_r) = unsafe { into_mut(heap.root) } {
+                // The new root takes over the trees buffered by lazy
+                // insertion.
+                let buffered = root_r.next;
+                root_r.next = ptr::null_mut();
+                unsafe {
+                    (*first).next = buffered;
+                    if let Some(buffered_r) = into_mut(buffered) {
+                        buffered_r.prev = first;
+                    }
+                    adopt(first, heap.root);
+                }
+            }
+            heap.root = first;
+        }
+
+        for tree in heap.tree_array.drain(..) {
+            if tree != first {
+                unsafe { adopt(first, tree) };
+            }
+        }
+    }
+}
+
+impl<'a, T, C, S> Drop for Detached<'a, T, C, S> {
+    fn drop(&mut self) {
+        for node in &mut self.rest {
+            let node_r = unsafe { &mut *node };
+            node_r.prev = ptr::null_mut();
+            node_r.next = ptr::null_mut();
+            self.heap.tree_array.push(node);
+        }
+
+        let heap = &mut *self.heap;
+        for tree in heap.tree_array.drain(..) {
+            if tree.is_null() { continue }
+
+            if heap.root.is_null() {
+                heap.root = tree;
+            } else {
+                unsafe { adopt(heap.root, tree) };
+            }
+        }
+    }
+}
+
+/// Yields every node reachable from a node through `first_child` and
+/// `next`, exactly once. The links of each node are no longer needed
+/// by the time it is yielded, so it may be freed or recycled.
+struct Dismantle<T> {
+    node: *mut Node<T>,
+}
+
+impl<T> Dismantle<T> {
+    /// The tree must not be used through any other path afterwards.
+    unsafe fn new(node: *mut Node<T>) -> Self {
+        Dismantle { node }
+    }
+}
+
+impl<T> Iterator for Dismantle<T> {
+    type Item = *mut Node<T>;
+
+    fn next(&mut self) -> Option<*mut Node<T>> {
+        // Treat `first_child` as the left link and `next` as the right
+        // link of a binary tree. Rotating every left child up into the
+        // right spine flattens the tree into a single list without
+        // recursion or extra storage.
+        while let Some(node_r) = unsafe { into_mut(self.node) } {
+            let child = node_r.first_child;
+
+            if let Some(child_r) = unsafe { into_mut(child) } {
+                node_r.first_child = child_r.next;
+                child_r.next = self.node;
+                self.node = child;
+            } else {
+                let node = self.node;
+                self.node = node_r.next;
+                return Some(node);
+            }
+        }
+        None
+    }
+}
+
+/// Unlinks a node that is not the root from its parent or previous
+/// sibling. The node keeps its own children.
+unsafe fn cut<T>(node: *mut Node<T>) {
+    let node_r = &mut *node;
+
+    if let Some(p_next_r) = into_mut(node_r.next) {
+        p_next_r.prev = node_r.prev;
+    }
+
+    let node_prev_r = &mut *node_r.prev;
+
+    if node_prev_r.first_child == node {
+        node_prev_r.first_child = node_r.next;
+    } else {
+        node_prev_r.next = node_r.next;
+    }
+
+    node_r.next = ptr::null_mut();
+}
+
+/// Makes a detached tree the first child of `parent`, without comparing.
+unsafe fn adopt<T>(parent: *mut Node<T>, child: *mut Node<T>) {
+    let parent_r = &mut *parent;
+    let child_r = &mut *child;
+
+    child_r.prev = parent;
+    child_r.next = parent_r.first_child;
+    if let Some(next_r) = into_mut(child_r.next) {
+        next_r.prev = child;
+    }
+    parent_r.first_child = child;
+}
+
+/// Links two trees, returning the new root. The comparison happens
+/// before any pointer is changed, so a panicking comparator leaves both
+/// trees as they were.
+fn compare_and_link<T, C>(compare: &C, stable: bool, first: *mut Node<T>, second: *mut Node<T>) -> *mut Node<T>
+    where C: Compare<T>,
+{
+    if second.is_null() { return first }
+
+    let second_first = comes_before(compare, stable, second, first);
+    unsafe { link(first, second, second_first) }
+}
+
+/// Like `compare_and_link` for a root, but the trees buffered after it
+/// by lazy insertion stay with whichever of the two ends up on top.
+fn link_with_root<T, C>(compare: &C, stable: bool, root: *mut Node<T>, tree: *mut Node<T>) -> *mut Node<T>
+    where C: Compare<T>,
+{
+    if tree.is_null() { return root }
+
+    let tree_first = comes_before(compare, stable, tree, root);
+
+    unsafe {
+        let buffered = (*root).next;
+        (*root).next = ptr::null_mut();
+
+        let root = link(root, tree, tree_first);
+        (*root).next = buffered;
+        if let Some(buffered_r) = into_mut(buffered) {
+            buffered_r.prev = root;
+        }
+        root
+    }
+}
+
+/// Whether node `a` should be popped before node `b`. In stable mode,
+/// equal values are popped in the order they were pushed.
+fn comes_before<T, C>(compare: &C, stable: bool, a: *mut Node<T>, b: *mut Node<T>) -> bool
+    where C: Compare<T>,
+{
+    let a_r = unsafe { &*a };
+    let b_r = unsafe { &*b };
+
+    match compare.compare(&a_r.value, &b_r.value) {
+        Ordering::Equal => stable && a_r.seq < b_r.seq,
+        ordering => ordering == Ordering::Less,
+    }
+}
+
+/// Makes one tree a child of the other, returning the new root.
+unsafe fn link<T>(first: *mut Node<T>, second: *mut Node<T>, second_first: bool) -> *mut Node<T> {
+    let first_r = &mut *first;
+    let second_r = &mut *second;
+
+    if second_first {
+        second_r.prev = first_r.prev;
+        first_r.prev = second;
+        first_r.next = second_r.first_child;
+        if let Some(first_next_r) = into_mut(first_r.next) {
+            first_next_r.prev = first;
+        }
+        second_r.first_child = first;
+        second
+    } else {
+        second_r.prev = first;
+        first_r.next = second_r.next;
+        if let Some(first_next_r) = into_mut(first_r.next) {
+            first_next_r.prev = first;
+        }
+        second_r.next = first_r.first_child;
+        if let Some(second_next_r) = into_mut(second_r.next) {
+            second_next_r.prev = second;
+        }
+        first_r.first_child = second;
+        first
+    }
+}
+
+unsafe fn into_mut<'a, T>(v: *mut T) -> Option<&'a mut T> {
+    if v.is_null() {
+        None
+    } else {
+        Some(&mut *v)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use unwind;
+    use {Drain, Heap, Iter, IterWithTokens, MaxHeap, StaleToken, Token};
+    use std::cell::Cell;
+    use std::cmp::Ordering;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::rc::Rc;
+
+    /// Orders by the number, counting how many times it is dropped.
+    struct Counted(u32, Rc<Cell<usize>>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.1.set(self.1.get() + 1);
+        }
+    }
+
+    impl PartialEq for Counted {
+        fn eq(&self, other: &Self) -> bool { self.0 == other.0 }
+    }
+    impl Eq for Counted {}
+    impl PartialOrd for Counted {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+    }
+    impl Ord for Counted {
+        fn cmp(&self, other: &Self) -> Ordering { self.0.cmp(&other.0) }
+    }
+
+    #[test]
+    fn empty_heap_pops_none() {
+        let mut h = Heap::<u8>::new();
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn heap_with_one_value() {
+        let mut h = Heap::new();
+        h.push(1);
+        assert_eq!(Some(1), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn peek_shows_the_value_pop_would_return() {
+        let mut h = Heap::new();
+        assert_eq!(None, h.peek());
+
+        h.push(2);
+        h.push(1);
+        assert_eq!(Some(&1), h.peek());
+        assert_eq!(Some(1), h.pop());
+        assert_eq!(Some(&2), h.peek());
+    }
+
+    #[test]
+    fn a_comparator_closure_decides_the_order() {
+        let by_len = |a: &&str, b: &&str| a.len().cmp(&b.len());
+        let mut h = Heap::with_comparator(by_len);
+
+        h.push("three");
+        h.push("a");
+        let t = h.push("sixsix");
+        h.push("to");
+
+        assert_eq!(Some("a"), h.pop());
+        h.decrease_key(t, |v| *v = "");
+        assert_eq!(Some(""), h.pop());
+        assert_eq!(Some("to"), h.pop());
+        assert_eq!(Some("three"), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn a_comparator_can_be_chosen_at_runtime() {
+        let reverse = true;
+        let mut h = Heap::with_comparator(move |a: &i32, b: &i32| {
+            if reverse { b.cmp(a) } else { a.cmp(b) }
+        });
+
+        for i in 0..10 { h.push(i); }
+        for i in (0..10).rev() {
+            assert_eq!(Some(i), h.pop());
+        }
+    }
+
+    #[test]
+    fn multiple_values_inserted_in_order_returns_them_in_order() {
+        let mut h = Heap::new();
+        h.push(1);
+        h.push(2);
+        assert_eq!(Some(1), h.pop());
+        assert_eq!(Some(2), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn multiple_values_inserted_out_of_order_returns_them_in_order() {
+        let mut h = Heap::new();
+        h.push(2);
+        h.push(1);
+        assert_eq!(Some(1), h.pop());
+        assert_eq!(Some(2), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn duplicate_values_are_kept() {
+        let mut h = Heap::new();
+
+        for i in 0..5 { h.push(i); }
+        for i in 0..5 { h.push(i); }
+
+        for i in 0..5 {
+            assert_eq!(Some(i), h.pop());
+            assert_eq!(Some(i), h.pop());
+        }
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn duplicate_values_are_kept_in_order_when_stable() {
+        let mut h = Heap::with_comparator(|a: &(u32, u32), b: &(u32, u32)| a.0.cmp(&b.0));
+        h.set_stable(true);
+
+        for i in 0..5 { h.push((i, 0)); }
+        for i in 0..5 { h.push((i, 1)); }
+        for i in (0..5).rev() { h.push((i, 2)); }
+
+        for i in 0..5 {
+            assert_eq!(Some((i, 0)), h.pop());
+            assert_eq!(Some((i, 1)), h.pop());
+            assert_eq!(Some((i, 2)), h.pop());
+        }
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn stable_order_survives_every_operation() {
+        for &lazy_insert in &[false, true] {
+            let mut h = Heap::with_comparator(|a: &(u32, u32), b: &(u32, u32)| a.0.cmp(&b.0));
+            h.set_lazy_insert(lazy_insert);
+
+            // Turning stable mode on relinks what is already there.
+            let tokens: Vec<_> = (0..60).map(|i| h.push((i % 4 + 10, i))).collect();
+            h.set_stable(true);
+            assert!(h.stable());
+
+            for &t in tokens.iter().step_by(5) {
+                h.decrease_key(t, |v| v.0 = 1);
+            }
+            for &t in tokens.iter().skip(2).step_by(7) {
+                h.increase_key(t, |v| v.0 = 20);
+            }
+            h.remove(tokens[3]);
+            let mut h = h.clone();
+            h.extend((60..70).map(|i| (i % 4 + 10, i)));
+
+            let values = h.into_sorted_vec();
+            for pair in values.windows(2) {
+                assert!(pair[0].0 < pair[1].0 || (pair[0].0 == pair[1].0 && pair[0].1 < pair[1].1),
+                        "{:?} came before {:?}", pair[0], pair[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn interleaved_push_and_pop() {
+        let mut h = Heap::new();
+
+        h.push(0);
+        assert_eq!(Some(0), h.pop());
+
+        h.push(1);
+        h.push(1);
+        assert_eq!(Some(1), h.pop());
+
+        h.push(2);
+        assert_eq!(Some(1), h.pop());
+        assert_eq!(Some(2), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn many_values_inserted_returns_them_in_order() {
+        let count = 123;
+
+        let mut h = Heap::new();
+        for i in 0..count {
+            h.push(i);
+        }
+
+        for i in 0..count {
+            assert_eq!(Some(i), h.pop());
+        }
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn decreasing_a_key_brings_it_to_the_front() {
+        let mut h = Heap::new();
+
+        h.push(10);
+        let t2 = h.push(20);
+
+        h.decrease_key(t2, |v| *v = 5);
+
+        assert_eq!(Some(5), h.pop());
+        assert_eq!(Some(10), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn decreasing_a_key_of_many_brings_it_to_the_front() {
+        let mut h = Heap::new();
+
+        let mut t = h.push(10);
+        for i in 11..100 {
+            t = h.push(i);
+        }
+
+        h.decrease_key(t, |v| *v = 1);
+
+        assert_eq!(Some(1), h.pop());
+
+        for i in 10..99 {
+            assert_eq!(Some(i), h.pop());
+        }
+
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn dropping_the_heap_drops_remaining_values() {
+        let drops = Rc::new(Cell::new(0));
+
+        {
+            let mut h = Heap::new();
+            for i in 0..100 {
+                h.push(Counted(i, drops.clone()));
+            }
+            for _ in 0..10 {
+                h.pop();
+            }
+            assert_eq!(10, drops.get());
+        }
+
+        assert_eq!(100, drops.get());
+    }
+
+    #[test]
+    fn dropping_a_deep_heap_does_not_overflow_the_stack() {
+        let mut h = Heap::new();
+        for i in (0..1_000_000).rev() {
+            h.push(i);
+        }
+    }
+
+    #[test]
+    fn decreasing_a_popped_key_is_an_error() {
+        let mut h = Heap::new();
+
+        let t = h.push(10);
+        h.push(20);
+        assert_eq!(Some(10), h.pop());
+
+        assert_eq!(Err(StaleToken), h.try_decrease_key(t, |v| *v = 5));
+        assert_eq!(Some(20), h.pop());
+    }
+
+    #[test]
+    fn tokens_are_stale_once_their_node_is_reused() {
+        let mut h = Heap::new();
+
+        let old = h.push(10);
+        assert_eq!(Some(10), h.pop());
+        let new = h.push(20);
+
+        assert_eq!(Err(StaleToken), h.try_decrease_key(old, |v| *v = 1));
+        assert_eq!(Ok(()), h.try_decrease_key(new, |v| *v = 5));
+        assert_eq!(Some(5), h.pop());
+    }
+
+    #[test]
+    fn decreasing_a_key_from_another_heap_is_an_error() {
+        let mut h1 = Heap::new();
+        let mut h2 = Heap::new();
+
+        let t = h1.push(10);
+        h2.push(20);
+
+        assert_eq!(Err(StaleToken), h2.try_decrease_key(t, |v| *v = 5));
+        assert_eq!(Some(20), h2.pop());
+        assert_eq!(Some(10), h1.pop());
+    }
+
+    #[test]
+    #[should_panic]
+    fn decrease_key_panics_on_a_stale_token() {
+        let mut h = Heap::new();
+
+        let t = h.push(10);
+        h.pop();
+        h.decrease_key(t, |v| *v = 5);
+    }
+
+    #[test]
+    fn removing_the_root() {
+        let mut h = Heap::new();
+
+        let t = h.push(1);
+        h.push(2);
+        h.push(3);
+
+        assert_eq!(1, h.remove(t));
+        assert_eq!(Some(2), h.pop());
+        assert_eq!(Some(3), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn removing_values_from_the_middle_keeps_the_rest_in_order() {
+        let mut h = Heap::new();
+
+        let tokens: Vec<_> = (0..50).map(|i| h.push(i)).collect();
+        h.pop();
+        h.pop();
+
+        for &i in &[10, 49, 25, 3, 26] {
+            assert_eq!(i, h.remove(tokens[i]));
+        }
+
+        for i in (2..49).filter(|i| ![10, 25, 3, 26].contains(i)) {
+            assert_eq!(Some(i), h.pop());
+        }
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn removing_a_value_with_children() {
+        let mut h = Heap::new();
+
+        for i in 10..20 { h.push(i); }
+        let t = h.push(5);
+        for i in 20..30 { h.push(i); }
+        h.decrease_key(t, |v| *v = 5);
+        h.push(0);
+
+        assert_eq!(5, h.remove(t));
+        for i in (0..1).chain(10..30) {
+            assert_eq!(Some(i), h.pop());
+        }
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn removing_twice_is_an_error() {
+        let mut h = Heap::new();
+
+        let t = h.push(1);
+        h.push(2);
+
+        assert_eq!(Ok(1), h.try_remove(t));
+        assert_eq!(Err(StaleToken), h.try_remove(t));
+    }
+
+    #[test]
+    fn increasing_a_key_sends_it_to_the_back() {
+        let mut h = Heap::new();
+
+        let t = h.push(1);
+        for i in 2..10 { h.push(i); }
+
+        h.increase_key(t, |v| *v = 20);
+
+        for i in 2..10 {
+            assert_eq!(Some(i), h.pop());
+        }
+        assert_eq!(Some(20), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn increasing_a_key_in_the_middle() {
+        let mut h = Heap::new();
+
+        let tokens: Vec<_> = (0..30).map(|i| h.push(i)).collect();
+        h.pop();
+        h.increase_key(tokens[5], |v| *v = 15);
+        h.increase_key(tokens[12], |v| *v = 100);
+
+        for i in (1..30).filter(|&i| i != 5 && i != 12) {
+            assert_eq!(Some(i), h.pop());
+            if i == 15 { assert_eq!(Some(15), h.pop()); }
+        }
+        assert_eq!(Some(100), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn updating_a_key_in_either_direction() {
+        let mut h = Heap::new();
+
+        let tokens: Vec<_> = (0..10).map(|i| h.push(i * 10)).collect();
+
+        h.update_key(tokens[0], |v| *v = 55);
+        h.update_key(tokens[9], |v| *v = 5);
+        h.update_key(tokens[4], |v| *v = 41);
+
+        let mut values = Vec::new();
+        while let Some(v) = h.pop() { values.push(v) }
+        assert_eq!(vec![5, 10, 20, 30, 41, 50, 55, 60, 70, 80], values);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Value moved towards the back of the heap")]
+    fn increasing_a_key_with_decrease_key_is_caught() {
+        let mut h = Heap::new();
+
+        let t = h.push(1);
+        h.push(2);
+        h.decrease_key(t, |v| *v = 3);
+    }
+
+    #[test]
+    fn appending_moves_all_values() {
+        let mut h1 = Heap::new();
+        let mut h2 = Heap::new();
+
+        for i in 0..10 { h1.push(i * 2); }
+        for i in 0..10 { h2.push(i * 2 + 1); }
+
+        h1.append(&mut h2);
+        assert_eq!(None, h2.pop());
+
+        for i in 0..20 {
+            assert_eq!(Some(i), h1.pop());
+        }
+        assert_eq!(None, h1.pop());
+    }
+
+    #[test]
+    fn appending_to_and_from_empty_heaps() {
+        let mut h1 = Heap::new();
+        let mut h2 = Heap::new();
+
+        h1.append(&mut h2);
+        assert_eq!(None, h1.pop());
+
+        h2.push(1);
+        h1.append(&mut h2);
+        h1.append(&mut h2);
+        assert_eq!(Some(1), h1.pop());
+        assert_eq!(None, h1.pop());
+    }
+
+    #[test]
+    fn appending_empty_heaps_does_not_slow_down_lookups() {
+        let mut h = Heap::new();
+        let t = h.push(1);
+
+        let mut other = Heap::new();
+        let popped = other.push(2);
+        other.pop();
+        for _ in 0..1000 {
+            h.append(&mut other);
+            h.append(&mut Heap::new());
+        }
+        assert!(h.adopted.is_empty());
+        assert_eq!(Err(StaleToken), h.try_decrease_key(popped, |v| *v = 0));
+
+        // Adopted IDs are passed along, but each one only once.
+        let mut tokens = vec![t];
+        for i in 0..100 {
+            let mut other = Heap::new();
+            tokens.push(other.push(i + 10));
+            h.append(&mut other);
+            other.append(&mut h);
+            h.append(&mut other);
+        }
+        assert!(h.adopted.len() <= 300);
+        for &t in &tokens {
+            assert!(h.try_get(t).is_some());
+        }
+    }
+
+    #[test]
+    fn tokens_of_a_melded_heap_stay_valid() {
+        let mut h1 = Heap::new();
+        let mut h2 = Heap::new();
+        let mut h3 = Heap::new();
+
+        h1.push(10);
+        let t2 = h2.push(20);
+        let t3 = h3.push(30);
+        let popped = h3.push(0);
+        h3.pop();
+
+        h2.append(&mut h3);
+        let mut h = h1.meld(h2);
+
+        h.decrease_key(t3, |v| *v = 5);
+        h.decrease_key(t2, |v| *v = 6);
+        assert_eq!(Err(StaleToken), h.try_decrease_key(popped, |v| *v = 1));
+
+        assert_eq!(Some(5), h.pop());
+        assert_eq!(Some(6), h.pop());
+        assert_eq!(Some(10), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn tokens_do_not_follow_into_an_emptied_heap() {
+        let mut h1 = Heap::new();
+        let mut h2 = Heap::new();
+
+        let t = h2.push(20);
+        h1.append(&mut h2);
+        h2.push(30);
+
+        assert_eq!(Err(StaleToken), h2.try_decrease_key(t, |v| *v = 5));
+        assert_eq!(Ok(()), h1.try_decrease_key(t, |v| *v = 5));
+    }
+
+    #[test]
+    fn length_follows_every_change() {
+        let mut h = Heap::new();
+        assert!(h.is_empty());
+
+        let t = h.push(3);
+        h.push(1);
+        let u = h.push(2);
+        assert_eq!(3, h.len());
+
+        h.decrease_key(t, |v| *v = 0);
+        h.increase_key(u, |v| *v = 5);
+        assert_eq!(3, h.len());
+
+        h.pop();
+        h.remove(u);
+        assert_eq!(1, h.len());
+
+        let mut other = Heap::new();
+        other.push(4);
+        other.push(5);
+        h.append(&mut other);
+        assert_eq!(3, h.len());
+        assert_eq!(0, other.len());
+        assert!(other.is_empty());
+    }
+
+    #[test]
+    fn clearing_drops_every_value() {
+        let drops = Rc::new(Cell::new(0));
+
+        let mut h = Heap::new();
+        let t = h.push(Counted(0, drops.clone()));
+        for i in 1..10 {
+            h.push(Counted(i, drops.clone()));
+        }
+
+        h.clear();
+        assert_eq!(10, drops.get());
+        assert!(h.is_empty());
+        assert!(h.peek().is_none());
+        assert_eq!(Err(StaleToken), h.try_remove(t).map(|_| ()));
+
+        h.push(Counted(5, drops.clone()));
+        assert_eq!(1, h.len());
+        assert_eq!(5, h.pop().unwrap().0);
+    }
+
+    #[test]
+    fn clearing_frees_every_node() {
+        let mut h = Heap::new();
+        for i in 0..100 { h.push(i); }
+        for _ in 0..50 { h.pop(); }
+        assert!(!h.free.is_null());
+
+        h.clear();
+        assert!(h.root.is_null());
+        assert!(h.free.is_null());
+        assert!(h.free_tail.is_null());
+
+        // A partly run drain frees the rest of its nodes when dropped.
+        let t = h.push(1);
+        h.extend(2..10);
+        h.pop();
+        assert_eq!(3, h.drain().take(3).count());
+        assert!(h.free.is_null());
+        assert_eq!(None, h.try_get(t));
+
+        h.push(7);
+        assert_eq!(Some(7), h.pop());
+    }
+
+    #[test]
+    fn retaining_some_values() {
+        let drops = Rc::new(Cell::new(0));
+
+        let mut h = Heap::new();
+        let tokens: Vec<_> = (0..30).map(|i| h.push(Counted(i, drops.clone()))).collect();
+        h.pop();
+        assert_eq!(1, drops.get());
+
+        h.retain(|v| v.0 % 3 == 0);
+        assert_eq!(9, h.len());
+        assert_eq!(21, drops.get());
+
+        h.decrease_key(tokens[27], |v| v.0 = 0);
+        assert!(h.try_remove(tokens[4]).is_err());
+
+        let mut values = Vec::new();
+        while let Some(v) = h.pop() { values.push(v.0) }
+        assert_eq!(vec![0, 3, 6, 9, 12, 15, 18, 21, 24], values);
+    }
+
+    #[test]
+    fn retaining_and_changing_values() {
+        let mut h: Heap<_> = (0..10).collect();
+
+        h.retain_mut(|v| {
+            *v = 20 - *v;
+            *v > 14
+        });
+
+        assert_eq!(vec![15, 16, 17, 18, 19, 20], h.into_sorted_vec());
+    }
+
+    #[test]
+    fn retaining_nothing() {
+        let mut h: Heap<_> = (0..10).collect();
+
+        h.retain(|_| false);
+        assert!(h.is_empty());
+        assert_eq!(None, h.pop());
+    }
+
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+
+    #[test]
+    fn heaps_are_send_and_sync_when_their_contents_are() {
+        assert_send::<Heap<String>>();
+        assert_sync::<Heap<String>>();
+        assert_send::<MaxHeap<String>>();
+        assert_sync::<MaxHeap<String>>();
+        assert_send::<Heap<Cell<u8>, fn(&Cell<u8>, &Cell<u8>) -> Ordering>>();
+
+        assert_sync::<Iter<String>>();
+        assert_send::<Iter<String>>();
+        assert_sync::<IterWithTokens<String>>();
+        assert_send::<IterWithTokens<String>>();
+        assert_send::<Drain<String, ::Min>>();
+    }
+
+    #[test]
+    fn tokens_are_always_send_and_sync() {
+        assert_send::<Token<Rc<u8>>>();
+        assert_sync::<Token<Cell<u8>>>();
+    }
+
+    #[test]
+    fn heaps_can_move_between_threads() {
+        let mut h: Heap<_> = (0..100).map(|i| i.to_string()).collect();
+        let t = h.push("".to_string());
+
+        let h = ::std::thread::spawn(move || {
+            h.remove(t);
+            h
+        }).join().unwrap();
+
+        assert_eq!(Some(&"0".to_string()), h.peek());
+    }
+
+    #[test]
+    fn reading_values_through_tokens() {
+        let mut h = Heap::new();
+
+        let t1 = h.push(10);
+        let t2 = h.push(20);
+
+        assert_eq!(&20, h.get(t2));
+        assert!(h.is_root(t1));
+        assert!(!h.is_root(t2));
+
+        h.decrease_key(t2, |v| *v = 5);
+        assert_eq!(Some(&5), h.try_get(t2));
+        assert!(h.is_root(t2));
+
+        h.pop();
+        assert_eq!(None, h.try_get(t2));
+        assert!(!h.contains(t2));
+        assert!(!h.is_root(t2));
+        assert!(h.contains(t1));
+        assert!(h.is_root(t1));
+
+        let other = Heap::<i32>::new();
+        assert_eq!(None, other.try_get(t1));
+        assert!(!other.contains(t1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to get value")]
+    fn getting_a_popped_value_panics() {
+        let mut h = Heap::new();
+
+        let t = h.push(10);
+        h.pop();
+        h.get(t);
+    }
+
+    /// Walks the tree checking every back link, returning the number of
+    /// nodes found.
+    fn check_links<T, C, S>(h: &Heap<T, C, S>) -> usize {
+        let mut count = 0;
+        let mut stack = Vec::new();
+
+        if let Some(root_r) = unsafe { super::into_mut(h.root) } {
+            assert!(root_r.prev.is_null());
+            stack.push(h.root);
+
+            let mut prev = h.root;
+            let mut buffered = root_r.next;
+            while let Some(buffered_r) = unsafe { super::into_mut(buffered) } {
+                assert_eq!(prev, buffered_r.prev);
+                stack.push(buffered);
+                prev = buffered;
+                buffered = buffered_r.next;
+            }
+        }
+
+        while let Some(node) = stack.pop() {
+            count += 1;
+
+            let mut prev = node;
+            let mut child = unsafe { (*node).first_child };
+            while let Some(child_r) = unsafe { super::into_mut(child) } {
+                assert_eq!(prev, child_r.prev);
+                stack.push(child);
+                prev = child;
+                child = child_r.next;
+            }
+        }
+        count
+    }
+
+    type FragileHeap = Heap<Counted, fn(&Counted, &Counted) -> Ordering>;
+
+    /// Runs `op` on a heap of twenty values with the comparator failing
+    /// after every possible number of calls, checking that the heap is
+    /// still sound and that every value is dropped exactly once.
+    fn check_unwinding<F>(op: F)
+        where F: Fn(&mut FragileHeap, &[Token<Counted>]),
+    {
+        for &lazy_insert in &[false, true] {
+            unwind::check(|| {
+                let drops = Rc::new(Cell::new(0));
+                let mut h: FragileHeap = Heap::with_comparator(unwind::compare);
+                h.set_lazy_insert(lazy_insert);
+                let tokens: Vec<_> = (0..20).map(|i| h.push(Counted(i * 7 % 20, drops.clone()))).collect();
+                h.pop();
+                (h, tokens, drops)
+            }, |&mut (ref mut h, ref tokens, _)| {
+                op(h, tokens)
+            }, |(mut h, _, drops)| {
+                assert_eq!(h.len(), check_links(&h));
+                assert_eq!(h.len(), h.iter().count());
+
+                h.push(Counted(100, drops.clone()));
+                drop(h);
+                assert_eq!(21, drops.get());
+            });
+        }
+    }
+
+    #[test]
+    fn comparator_panicking_during_push_keeps_the_value() {
+        check_unwinding(|h, _| { h.push(Counted(3, Rc::new(Cell::new(0)))); });
+    }
+
+    #[test]
+    fn comparator_panicking_during_extend_keeps_every_value() {
+        check_unwinding(|h, _| h.extend((0..5).map(|i| Counted(i, Rc::new(Cell::new(0))))));
+    }
+
+    #[test]
+    fn comparator_panicking_during_pop_loses_no_nodes() {
+        check_unwinding(|h, _| while h.pop().is_some() {});
+    }
+
+    #[test]
+    fn comparator_panicking_during_remove_loses_no_nodes() {
+        check_unwinding(|h, tokens| {
+            for &t in tokens.iter().rev() {
+                let _ = h.try_remove(t);
+            }
+        });
+    }
+
+    #[test]
+    fn comparator_panicking_during_key_changes_loses_no_nodes() {
+        check_unwinding(|h, tokens| {
+            for &t in tokens {
+                let _ = h.try_increase_key(t, |v| v.0 += 20);
+                let _ = h.try_decrease_key(t, |v| v.0 -= 15);
+                let _ = h.try_update_key(t, |v| v.0 += 7);
+            }
+        });
+    }
+
+    #[test]
+    fn comparator_panicking_during_retain_loses_no_nodes() {
+        check_unwinding(|h, _| h.retain(|v| v.0 % 3 != 0));
+    }
+
+    #[test]
+    fn comparator_panicking_during_peek_mut_loses_no_nodes() {
+        check_unwinding(|h, _| {
+            for _ in 0..5 {
+                h.peek_mut().unwrap().0 += 30;
+            }
+        });
+    }
+
+    #[test]
+    fn closure_panicking_during_retain_loses_no_nodes() {
+        let drops = Rc::new(Cell::new(0));
+
+        let mut h = Heap::new();
+        for i in 0..20 {
+            h.push(Counted(i, drops.clone()));
+        }
+        h.pop();
+
+        let mut seen = 0;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            h.retain(|v| {
+                seen += 1;
+                if seen == 10 { panic!("Closure panicked") }
+                v.0 % 2 == 0
+            })
+        }));
+        assert!(result.is_err());
+
+        assert_eq!(h.len(), check_links(&h));
+        assert_eq!(h.len() + drops.get(), 20);
+
+        drop(h);
+        assert_eq!(20, drops.get());
+    }
+
+    #[test]
+    fn closure_panicking_during_decrease_key_leaves_the_node_linked() {
+        let mut h = Heap::new();
+        let tokens: Vec<_> = (0..10).map(|i| h.push(i)).collect();
+        h.pop();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            h.decrease_key(tokens[5], |_| panic!("Closure panicked"))
+        }));
+        assert!(result.is_err());
+
+        assert_eq!(9, check_links(&h));
+        assert_eq!(5, *h.get(tokens[5]));
+        assert_eq!((1..10).collect::<Vec<_>>(), h.into_sorted_vec());
+    }
+
+    #[test]
+    fn destructor_panicking_during_clear_still_drops_the_rest() {
+        struct Bomb(u32, Rc<Cell<usize>>);
+
+        impl Drop for Bomb {
+            fn drop(&mut self) {
+                self.1.set(self.1.get() + 1);
+                if self.0 == 4 { panic!("Destructor panicked") }
+            }
+        }
+
+        let drops = Rc::new(Cell::new(0));
+        let mut h = Heap::with_comparator(|a: &Bomb, b: &Bomb| a.0.cmp(&b.0));
+        for i in 0..10 {
+            h.push(Bomb(i, drops.clone()));
+        }
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| h.clear()));
+        assert!(result.is_err());
+        assert_eq!(10, drops.get());
+        assert!(h.is_empty());
+    }
+
+    #[test]
+    fn lazy_insertion_buffers_until_pop() {
+        let mut h = Heap::new();
+        h.set_lazy_insert(true);
+        assert!(h.lazy_insert());
+
+        let tokens: Vec<_> = [5, 3, 8, 1, 9, 2, 7].iter().map(|&v| h.push(v)).collect();
+        assert_eq!(7, check_links(&h));
+        assert_eq!(Some(&1), h.peek());
+        // Everything but the root is still waiting in the buffer.
+        assert!(unsafe { (*h.root).first_child.is_null() });
+
+        assert_eq!(Some(1), h.pop());
+        assert_eq!(Some(&2), h.peek());
+        assert_eq!(6, check_links(&h));
+
+        assert_eq!(vec![2, 3, 5, 7, 8, 9], h.clone().into_sorted_vec());
+
+        h.push(6);
+        h.decrease_key(tokens[4], |v| *v = 0);
+        assert_eq!(Some(&0), h.peek());
+        assert_eq!(vec![0, 2, 3, 5, 6, 7, 8], h.into_sorted_vec());
+    }
+
+    #[test]
+    fn lazy_insertion_keeps_tokens_for_buffered_values() {
+        let mut h = Heap::new();
+        h.set_lazy_insert(true);
+
+        let a = h.push(10);
+        let b = h.push(20);
+        let c = h.push(30);
+
+        h.increase_key(b, |v| *v = 40);
+        assert_eq!(30, h.remove(c));
+        h.update_key(a, |v| *v = 50);
+        assert_eq!(Some(&40), h.peek());
+        assert!(h.is_root(b));
+
+        *h.peek_mut().unwrap() = 60;
+        assert_eq!(Some(&50), h.peek());
+        assert_eq!(2, check_links(&h));
+        assert_eq!(vec![50, 60], h.into_sorted_vec());
+    }
+
+    #[test]
+    fn updating_the_root_folds_the_buffer_once() {
+        let comparisons = Cell::new(0);
+        let mut h = Heap::with_comparator(|a: &u32, b: &u32| {
+            comparisons.set(comparisons.get() + 1);
+            a.cmp(b)
+        });
+        h.set_lazy_insert(true);
+
+        let root = h.push(0);
+        for i in 1..10_000 { h.push(i); }
+
+        comparisons.set(0);
+        for _ in 0..100 {
+            h.update_key(root, |_| {});
+            h.decrease_key(root, |_| {});
+        }
+        assert!(comparisons.get() < 20_000, "{} comparisons", comparisons.get());
+        assert_eq!(Some(&0), h.peek());
+        assert_eq!(10_000, check_links(&h));
+    }
+
+    #[test]
+    fn appending_a_lazy_heap_folds_its_buffer() {
+        let mut a = Heap::new();
+        a.set_lazy_insert(true);
+        a.extend(vec![4, 2, 6]);
+
+        let mut b = Heap::new();
+        b.set_lazy_insert(true);
+        let t = b.push(5);
+        b.extend(vec![1, 3]);
+
+        a.append(&mut b);
+        assert_eq!(6, check_links(&a));
+        assert_eq!(Some(&1), a.peek());
+        assert_eq!(5, *a.get(t));
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], a.into_sorted_vec());
     }
 }
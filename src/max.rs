@@ -0,0 +1,445 @@
+use std::collections::BinaryHeap;
+use std::iter::FromIterator;
+
+use {Drain, DrainSorted, Heap, IntoIterSorted, Iter, IterWithTokens, Max, PeekMut, StaleToken, Token};
+
+/// A heap that pops the largest value first, like
+/// `std::collections::BinaryHeap`.
+///
+/// Moving a value towards the front of this heap means making it
+/// larger, so the key operation is `increase_key`. Apart from swapping
+/// the names of `increase_key` and `decrease_key`, this has the same
+/// API as `Heap`. Only the comparator and combining strategy are fixed,
+/// and `Heap::scope` is left out; use `Heap<T, Max, S>` for those.
+#[derive(Debug, Clone)]
+pub struct MaxHeap<T> {
+    heap: Heap<T, Max>,
+}
+
+impl<T> MaxHeap<T>
+    where T: Ord,
+{
+    pub fn new() -> MaxHeap<T> {
+        MaxHeap { heap: Heap::with_comparator(Max) }
+    }
+
+    pub fn push(&mut self, value: T) -> Token<T> {
+        self.heap.push(value)
+    }
+
+    /// Pushes every value, returning a token for each in the same
+    /// order.
+    pub fn extend_with_tokens<I>(&mut self, iter: I) -> Vec<Token<T>>
+        where I: IntoIterator<Item = T>,
+    {
+        self.heap.extend_with_tokens(iter)
+    }
+
+    /// Moves every value of `other` into this heap, leaving `other`
+    /// empty. See `Heap::append`.
+    pub fn append(&mut self, other: &mut MaxHeap<T>) {
+        self.heap.append(&mut other.heap)
+    }
+
+    /// Combines two heaps into one. See `append`.
+    pub fn meld(self, other: MaxHeap<T>) -> MaxHeap<T> {
+        MaxHeap { heap: self.heap.meld(other.heap) }
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.heap.pop()
+    }
+
+    /// The value that `pop` would return next.
+    pub fn peek(&self) -> Option<&T> {
+        self.heap.peek()
+    }
+
+    /// The number of values in the heap.
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    /// Iterates over the values in arbitrary order.
+    pub fn iter(&self) -> Iter<'_, T> {
+        self.heap.iter()
+    }
+
+    /// Iterates over the values in arbitrary order, each with its
+    /// token.
+    pub fn iter_with_tokens(&self) -> IterWithTokens<'_, T> {
+        self.heap.iter_with_tokens()
+    }
+
+    /// The values in ascending order, like
+    /// `BinaryHeap::into_sorted_vec`.
+    pub fn into_sorted_vec(self) -> Vec<T> {
+        let mut values = self.heap.into_sorted_vec();
+        values.reverse();
+        values
+    }
+
+    /// The values in arbitrary order, like `BinaryHeap::into_vec`.
+    pub fn into_vec(self) -> Vec<T> {
+        self.heap.into_vec()
+    }
+
+    /// Consumes the heap, lazily popping its values from largest to
+    /// smallest.
+    pub fn into_iter_sorted(self) -> IntoIterSorted<T, Max> {
+        self.heap.into_iter_sorted()
+    }
+
+    /// Pops the values from largest to smallest, emptying the heap
+    /// even if the iterator is not run to completion.
+    pub fn drain_sorted(&mut self) -> DrainSorted<'_, T, Max> {
+        self.heap.drain_sorted()
+    }
+
+    /// Removes the values in arbitrary order, like `BinaryHeap::drain`.
+    /// Every token issued so far becomes stale.
+    pub fn drain(&mut self) -> Drain<'_, T, Max> {
+        self.heap.drain()
+    }
+
+    /// Keeps only the values for which `f` returns true.
+    pub fn retain<F>(&mut self, f: F)
+        where F: FnMut(&T) -> bool,
+    {
+        self.heap.retain(f)
+    }
+
+    /// Like `retain`, but `f` may also change the values it keeps.
+    pub fn retain_mut<F>(&mut self, f: F)
+        where F: FnMut(&mut T) -> bool,
+    {
+        self.heap.retain_mut(f)
+    }
+
+    /// See `Heap::set_stable`.
+    pub fn set_stable(&mut self, stable: bool) {
+        self.heap.set_stable(stable)
+    }
+
+    /// Whether equal values are popped in the order they were pushed.
+    pub fn stable(&self) -> bool {
+        self.heap.stable()
+    }
+
+    /// See `Heap::set_lazy_insert`.
+    pub fn set_lazy_insert(&mut self, lazy: bool) {
+        self.heap.set_lazy_insert(lazy)
+    }
+
+    /// Whether lazy insertion is on.
+    pub fn lazy_insert(&self) -> bool {
+        self.heap.lazy_insert()
+    }
+
+    /// Drops every value in the heap. Tokens for them become stale.
+    pub fn clear(&mut self) {
+        self.heap.clear()
+    }
+
+    /// The current value for a token.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn get(&self, token: Token<T>) -> &T {
+        self.heap.get(token)
+    }
+
+    /// Like `get`, but returns `None` for a stale or foreign token.
+    pub fn try_get(&self, token: Token<T>) -> Option<&T> {
+        self.heap.try_get(token)
+    }
+
+    /// Whether the token's value is still in this heap.
+    pub fn contains(&self, token: Token<T>) -> bool {
+        self.heap.contains(token)
+    }
+
+    /// Whether the token's value is the one `pop` would return next.
+    pub fn is_root(&self, token: Token<T>) -> bool {
+        self.heap.is_root(token)
+    }
+
+    /// Removes a value from anywhere in the heap.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn remove(&mut self, token: Token<T>) -> T {
+        match self.try_remove(token) {
+            Ok(value) => value,
+            Err(e) => panic!("Unable to remove: {}", e),
+        }
+    }
+
+    /// Like `remove`, but reports a stale or foreign token instead of
+    /// panicking.
+    pub fn try_remove(&mut self, token: Token<T>) -> Result<T, StaleToken> {
+        self.heap.try_remove(token)
+    }
+
+    /// Mutable access to the value that `pop` would return next. See
+    /// `PeekMut`.
+    pub fn peek_mut(&mut self) -> Option<PeekMut<'_, T, Max>> {
+        self.heap.peek_mut()
+    }
+
+    /// Do not decrease the key!
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn increase_key<F>(&mut self, token: Token<T>, f: F)
+        where F: FnOnce(&mut T),
+    {
+        if let Err(e) = self.try_increase_key(token, f) {
+            panic!("Unable to increase key: {}", e);
+        }
+    }
+
+    /// Like `increase_key`, but reports a stale or foreign token
+    /// instead of panicking. The closure is not called in that case.
+    pub fn try_increase_key<F>(&mut self, token: Token<T>, f: F) -> Result<(), StaleToken>
+        where F: FnOnce(&mut T),
+    {
+        self.heap.try_decrease_key(token, f)
+    }
+
+    /// Do not increase the key!
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn decrease_key<F>(&mut self, token: Token<T>, f: F)
+        where F: FnOnce(&mut T),
+    {
+        if let Err(e) = self.try_decrease_key(token, f) {
+            panic!("Unable to decrease key: {}", e);
+        }
+    }
+
+    /// Like `decrease_key`, but reports a stale or foreign token
+    /// instead of panicking. The closure is not called in that case.
+    pub fn try_decrease_key<F>(&mut self, token: Token<T>, f: F) -> Result<(), StaleToken>
+        where F: FnOnce(&mut T),
+    {
+        self.heap.try_increase_key(token, f)
+    }
+
+    /// Changes a value in either direction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn update_key<F>(&mut self, token: Token<T>, f: F)
+        where F: FnOnce(&mut T),
+    {
+        if let Err(e) = self.try_update_key(token, f) {
+            panic!("Unable to update key: {}", e);
+        }
+    }
+
+    /// Like `update_key`, but reports a stale or foreign token instead
+    /// of panicking. The closure is not called in that case.
+    pub fn try_update_key<F>(&mut self, token: Token<T>, f: F) -> Result<(), StaleToken>
+        where F: FnOnce(&mut T),
+    {
+        self.heap.try_update_key(token, f)
+    }
+}
+
+impl<T> Default for MaxHeap<T>
+    where T: Ord,
+{
+    fn default() -> Self {
+        MaxHeap::new()
+    }
+}
+
+impl<T> From<BinaryHeap<T>> for MaxHeap<T>
+    where T: Ord,
+{
+    fn from(heap: BinaryHeap<T>) -> Self {
+        MaxHeap { heap: heap.into() }
+    }
+}
+
+impl<T> From<MaxHeap<T>> for BinaryHeap<T>
+    where T: Ord,
+{
+    fn from(heap: MaxHeap<T>) -> Self {
+        heap.heap.into()
+    }
+}
+
+impl<T> Extend<T> for MaxHeap<T>
+    where T: Ord,
+{
+    fn extend<I>(&mut self, iter: I)
+        where I: IntoIterator<Item = T>,
+    {
+        self.heap.extend(iter);
+    }
+}
+
+impl<T> FromIterator<T> for MaxHeap<T>
+    where T: Ord,
+{
+    fn from_iter<I>(iter: I) -> Self
+        where I: IntoIterator<Item = T>,
+    {
+        MaxHeap { heap: iter.into_iter().collect() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BinaryHeap;
+    use MaxHeap;
+
+    #[test]
+    fn pops_in_the_same_order_as_binary_heap() {
+        let values = [5, 1, 9, 3, 3, 7, 0, 12, 9];
+
+        let mut h = MaxHeap::new();
+        let mut b = BinaryHeap::new();
+        for &v in &values {
+            h.push(v);
+            b.push(v);
+        }
+
+        while let Some(v) = b.pop() {
+            assert_eq!(Some(&v), h.peek());
+            assert_eq!(Some(v), h.pop());
+        }
+        assert_eq!(None, h.peek());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn sorted_vec_is_ascending_like_binary_heap() {
+        let values = vec![5, 1, 9, 3, 3, 7];
+
+        let mut h = MaxHeap::new();
+        for &v in &values { h.push(v); }
+        let b: BinaryHeap<_> = values.into_iter().collect();
+
+        assert_eq!(b.into_sorted_vec(), h.into_sorted_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to decrease key")]
+    fn decreasing_a_popped_value_names_the_right_method() {
+        let mut h = MaxHeap::new();
+
+        let t = h.push(1);
+        h.pop();
+        h.decrease_key(t, |v| *v = 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Value moved towards the back of the heap")]
+    fn decreasing_a_key_with_increase_key_is_caught() {
+        let mut h = MaxHeap::new();
+
+        let t = h.push(2);
+        h.push(1);
+        h.increase_key(t, |v| *v = 0);
+    }
+
+    #[test]
+    fn collecting_into_a_max_heap() {
+        let mut h: MaxHeap<_> = vec![5, 1, 9, 3].into_iter().collect();
+        h.extend(vec![7, 3]);
+
+        assert_eq!(6, h.len());
+        assert_eq!(vec![1, 3, 3, 5, 7, 9], h.into_sorted_vec());
+    }
+
+    #[test]
+    fn appending_and_draining_like_binary_heap() {
+        let mut a: MaxHeap<_> = vec![1, 5, 3].into_iter().collect();
+        let mut b = MaxHeap::new();
+        let t = b.push(4);
+        b.push(2);
+
+        a.append(&mut b);
+        assert!(b.is_empty());
+        assert!(a.contains(t));
+        assert_eq!(4, a.remove(t));
+        assert!(!a.contains(t));
+
+        let mut drained: Vec<_> = a.drain().collect();
+        drained.sort();
+        assert_eq!(vec![1, 2, 3, 5], drained);
+        assert!(a.is_empty());
+    }
+
+    #[test]
+    fn sorted_iterators_start_with_the_largest_value() {
+        let mut h: MaxHeap<_> = vec![2, 7, 4].into_iter().collect();
+        assert_eq!(vec![7, 4, 2], h.drain_sorted().collect::<Vec<_>>());
+
+        h.extend(vec![2, 7, 4]);
+        assert_eq!(vec![7, 4, 2], h.into_iter_sorted().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn converting_to_and_from_binary_heap() {
+        let b: BinaryHeap<_> = vec![3, 1, 2].into();
+
+        let mut h: MaxHeap<_> = b.into();
+        assert_eq!(Some(&3), h.peek());
+        h.push(4);
+
+        let b: BinaryHeap<_> = h.into();
+        assert_eq!(vec![1, 2, 3, 4], b.into_sorted_vec());
+    }
+
+    #[test]
+    fn increasing_a_key_brings_it_to_the_front() {
+        let mut h = MaxHeap::new();
+
+        h.push(10);
+        let t = h.push(5);
+        h.push(7);
+
+        h.increase_key(t, |v| *v = 20);
+
+        assert_eq!(Some(20), h.pop());
+        assert_eq!(Some(10), h.pop());
+        assert_eq!(Some(7), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn decreasing_a_key_sends_it_to_the_back() {
+        let mut h = MaxHeap::new();
+
+        let t = h.push(10);
+        h.push(5);
+        h.push(7);
+
+        h.decrease_key(t, |v| *v = 1);
+
+        assert_eq!(Some(7), h.pop());
+        assert_eq!(Some(5), h.pop());
+        assert_eq!(Some(1), h.pop());
+        assert_eq!(None, h.pop());
+    }
+}
@@ -0,0 +1,145 @@
+use std::ops::{Deref, DerefMut};
+
+use {CombineStrategy, Compare, Heap, Min, TwoPass};
+
+/// Mutable access to the front of a `Heap`, returned by `peek_mut`.
+///
+/// If the value is changed, the heap is put back in order when the
+/// guard is dropped by cutting off the root's children and melding
+/// them back in.
+pub struct PeekMut<'a, T: 'a, C: 'a + Compare<T> = Min, S: 'a + CombineStrategy = TwoPass> {
+    heap: &'a mut Heap<T, C, S>,
+    changed: bool,
+}
+
+impl<'a, T, C, S> PeekMut<'a, T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    /// Removes the peeked value from the heap and returns it.
+    pub fn pop(mut this: PeekMut<'a, T, C, S>) -> T {
+        this.changed = false;
+        this.heap.pop().expect("Peeked heap is empty")
+    }
+}
+
+impl<'a, T, C, S> Deref for PeekMut<'a, T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        let root_r = unsafe { &*self.heap.root };
+        &root_r.value
+    }
+}
+
+impl<'a, T, C, S> DerefMut for PeekMut<'a, T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    fn deref_mut(&mut self) -> &mut T {
+        self.changed = true;
+        let root_r = unsafe { &mut *self.heap.root };
+        &mut root_r.value
+    }
+}
+
+impl<'a, T, C, S> Drop for PeekMut<'a, T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    fn drop(&mut self) {
+        if self.changed {
+            let root = self.heap.root;
+            self.heap.move_children_to_root(root);
+        }
+    }
+}
+
+impl<T, C, S> Heap<T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    /// Mutable access to the value that `pop` would return next. See
+    /// `PeekMut`.
+    pub fn peek_mut(&mut self) -> Option<PeekMut<'_, T, C, S>> {
+        if self.root.is_null() { return None }
+
+        Some(PeekMut {
+            heap: self,
+            changed: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {Heap, MaxHeap, PeekMut};
+
+    #[test]
+    fn peek_mut_on_an_empty_heap_is_none() {
+        let mut h = Heap::<u8>::new();
+        assert!(h.peek_mut().is_none());
+    }
+
+    #[test]
+    fn changing_the_front_reorders_the_heap() {
+        let mut h = Heap::new();
+        for i in 0..10 { h.push(i); }
+
+        *h.peek_mut().unwrap() = 5;
+        assert_eq!(Some(&1), h.peek());
+
+        *h.peek_mut().unwrap() = 100;
+
+        for i in 2..10 {
+            assert_eq!(Some(i), h.pop());
+            if i == 5 { assert_eq!(Some(5), h.pop()); }
+        }
+        assert_eq!(Some(100), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn reading_through_peek_mut_does_not_change_anything() {
+        let mut h = Heap::new();
+        h.push(2);
+        h.push(1);
+
+        assert_eq!(1, *h.peek_mut().unwrap());
+        assert_eq!(Some(1), h.pop());
+        assert_eq!(Some(2), h.pop());
+    }
+
+    #[test]
+    fn popping_through_peek_mut() {
+        let mut h = Heap::new();
+        h.push(2);
+        h.push(1);
+
+        {
+            let mut top = h.peek_mut().unwrap();
+            *top = 0;
+            assert_eq!(0, PeekMut::pop(top));
+        }
+
+        assert_eq!(Some(2), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn peek_mut_on_a_max_heap() {
+        let mut h = MaxHeap::new();
+        for i in 0..5 { h.push(i); }
+
+        *h.peek_mut().unwrap() = 0;
+
+        for i in (0..4).rev() {
+            assert_eq!(Some(i), h.pop());
+        }
+        assert_eq!(Some(0), h.pop());
+        assert_eq!(None, h.pop());
+    }
+}
@@ -0,0 +1,169 @@
+use compare::ByPriority;
+use {Heap, StaleToken, Token};
+
+/// A heap of values ordered by a separate priority, smallest first.
+///
+/// Only the priority takes part in the ordering, so the value can be
+/// changed freely through `get_mut`, while the priority can only be
+/// lowered through `set_priority`.
+#[derive(Debug, Clone)]
+pub struct PriorityHeap<P, V> {
+    heap: Heap<(P, V), ByPriority>,
+}
+
+impl<P, V> PriorityHeap<P, V>
+    where P: Ord,
+{
+    pub fn new() -> PriorityHeap<P, V> {
+        PriorityHeap { heap: Heap::with_comparator(ByPriority) }
+    }
+
+    pub fn push(&mut self, priority: P, value: V) -> Token<(P, V)> {
+        self.heap.push((priority, value))
+    }
+
+    pub fn pop(&mut self) -> Option<(P, V)> {
+        self.heap.pop()
+    }
+
+    /// The entry that `pop` would return next.
+    pub fn peek(&self) -> Option<(&P, &V)> {
+        self.heap.peek().map(|entry| (&entry.0, &entry.1))
+    }
+
+    /// The number of values in the heap.
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    /// Drops every value in the heap. Tokens for them become stale.
+    pub fn clear(&mut self) {
+        self.heap.clear()
+    }
+
+    /// Lowers the priority of a value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `priority` is greater than the current priority, if
+    /// the token's value has already been popped or if the token came
+    /// from a different heap.
+    pub fn set_priority(&mut self, token: Token<(P, V)>, priority: P) {
+        if let Err(e) = self.try_set_priority(token, priority) {
+            panic!("Unable to set priority: {}", e);
+        }
+    }
+
+    /// Like `set_priority`, but reports a stale or foreign token
+    /// instead of panicking.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `priority` is greater than the current priority.
+    pub fn try_set_priority(&mut self, token: Token<(P, V)>, priority: P) -> Result<(), StaleToken> {
+        let node = self.heap.resolve(token)?;
+
+        let node_r = unsafe { &*node };
+        assert!(priority <= node_r.value.0, "Priority must not increase");
+
+        self.heap.decrease_node(node, |entry| entry.0 = priority);
+        Ok(())
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn get_mut(&mut self, token: Token<(P, V)>) -> &mut V {
+        match self.try_get_mut(token) {
+            Ok(value) => value,
+            Err(e) => panic!("Unable to get value: {}", e),
+        }
+    }
+
+    /// Like `get_mut`, but reports a stale or foreign token instead of
+    /// panicking.
+    pub fn try_get_mut(&mut self, token: Token<(P, V)>) -> Result<&mut V, StaleToken> {
+        let node = self.heap.resolve(token)?;
+        let node_r = unsafe { &mut *node };
+        Ok(&mut node_r.value.1)
+    }
+}
+
+impl<P, V> Default for PriorityHeap<P, V>
+    where P: Ord,
+{
+    fn default() -> Self {
+        PriorityHeap::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {PriorityHeap, StaleToken};
+
+    #[test]
+    fn entries_are_popped_by_priority() {
+        let mut h = PriorityHeap::new();
+
+        h.push(3, "c");
+        h.push(1, "a");
+        h.push(2, "b");
+
+        assert_eq!(Some((&1, &"a")), h.peek());
+        assert_eq!(Some((1, "a")), h.pop());
+        assert_eq!(Some((2, "b")), h.pop());
+        assert_eq!(Some((3, "c")), h.pop());
+        assert_eq!(None, h.pop());
+    }
+
+    #[test]
+    fn values_do_not_affect_the_order() {
+        let mut h = PriorityHeap::new();
+
+        h.push(1, 100);
+        let t = h.push(2, 0);
+
+        *h.get_mut(t) = -100;
+
+        assert_eq!(Some((1, 100)), h.pop());
+        assert_eq!(Some((2, -100)), h.pop());
+    }
+
+    #[test]
+    fn lowering_a_priority_brings_it_to_the_front() {
+        let mut h = PriorityHeap::new();
+
+        h.push(10, "a");
+        let t = h.push(20, "b");
+
+        h.set_priority(t, 5);
+
+        assert_eq!(Some((5, "b")), h.pop());
+        assert_eq!(Some((10, "a")), h.pop());
+    }
+
+    #[test]
+    #[should_panic(expected = "Priority must not increase")]
+    fn raising_a_priority_panics() {
+        let mut h = PriorityHeap::new();
+
+        let t = h.push(10, "a");
+        h.set_priority(t, 11);
+    }
+
+    #[test]
+    fn popped_tokens_are_an_error() {
+        let mut h = PriorityHeap::new();
+
+        let t = h.push(10, "a");
+        h.pop();
+
+        assert_eq!(Err(StaleToken), h.try_get_mut(t));
+        assert_eq!(Err(StaleToken), h.try_set_priority(t, 1));
+    }
+}
@@ -0,0 +1,579 @@
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+
+use slab::{Slab, NIL};
+use {Compare, HeapId, Min, StaleToken};
+
+/// A handle to a value in a `RankPairingHeap`, returned by `push`.
+///
+/// Like a `Token`, it remembers which heap issued it and which
+/// generation of the slot it refers to, so a popped or foreign token is
+/// reported instead of reaching an unrelated value.
+#[derive(Debug)]
+pub struct RankToken<T> {
+    index: u32,
+    heap: HeapId,
+    generation: u32,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Copy for RankToken<T> {}
+impl<T> Clone for RankToken<T> {
+    fn clone(&self) -> Self { *self }
+}
+
+struct Links {
+    rank: u32,
+    // The left child holds smaller ranked half trees whose values all
+    // come after this one. The right child is a sibling subtree that
+    // only comes after the parent.
+    left: u32,
+    right: u32,
+    // The node that has this one as its left or right child.
+    parent: u32,
+}
+
+impl Default for Links {
+    fn default() -> Self {
+        Links { rank: 0, left: NIL, right: NIL, parent: NIL }
+    }
+}
+
+/// A rank-pairing heap (Haeupler, Sen and Tarjan), with the same
+/// `push`, `pop` and `decrease_key` API as `Heap`.
+///
+/// `decrease_key` is O(1) amortized, which the pairing heap has never
+/// been proven to achieve. `pop` is O(log n) amortized. This uses the
+/// type-2 rank rule and one-pass linking, and keeps its nodes in a
+/// `Vec` linked by index like `ArenaHeap`.
+///
+/// Both heaps implement `TokenHeap`, which names their token type, so
+/// code can switch between them with a single alias:
+///
+/// ```
+/// use pairing_heap::{RankPairingHeap, TokenHeap};
+///
+/// type Queue = RankPairingHeap<u32>;
+///
+/// struct Job {
+///     token: <Queue as TokenHeap<u32>>::Token,
+/// }
+///
+/// let mut queue = Queue::new();
+/// queue.push(10);
+/// let job = Job { token: queue.push(20) };
+/// queue.decrease_key(job.token, |v| *v = 5);
+/// assert_eq!(Some(5), queue.pop());
+/// ```
+///
+/// Or be generic over the trait instead:
+///
+/// ```
+/// use pairing_heap::{Heap, RankPairingHeap, TokenHeap};
+///
+/// fn lower_last<H: TokenHeap<u32>>(mut queue: H) -> Option<u32> {
+///     queue.push(10);
+///     let t = queue.push(20);
+///     queue.decrease_key(t, |v| *v = 5);
+///     queue.pop()
+/// }
+///
+/// assert_eq!(Some(5), lower_last(Heap::new()));
+/// assert_eq!(Some(5), lower_last(RankPairingHeap::new()));
+/// ```
+pub struct RankPairingHeap<T, C = Min> {
+    id: HeapId,
+    slab: Slab<T, Links>,
+    // The roots of every half tree, in no particular order.
+    roots: Vec<u32>,
+    // The root that comes first.
+    min: u32,
+    len: usize,
+    // Scratch space for `pop`: the position in `roots` of a half tree
+    // waiting for another of the same rank, indexed by rank.
+    buckets: Vec<u32>,
+    compare: C,
+}
+
+impl<T> RankPairingHeap<T>
+    where T: Ord,
+{
+    pub fn new() -> RankPairingHeap<T> {
+        RankPairingHeap::with_comparator(Min)
+    }
+}
+
+impl<T, C> RankPairingHeap<T, C>
+    where C: Compare<T>,
+{
+    /// Creates a heap ordered by `compare`. See `Heap::with_comparator`.
+    pub fn with_comparator(compare: C) -> RankPairingHeap<T, C> {
+        RankPairingHeap {
+            id: HeapId::new(),
+            slab: Slab::with_capacity(0),
+            roots: Vec::new(),
+            min: NIL,
+            len: 0,
+            buckets: Vec::new(),
+            compare,
+        }
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the heap would need more than `u32::MAX - 1` slots.
+    pub fn push(&mut self, value: T) -> RankToken<T> {
+        let index = self.slab.insert(value);
+        self.len += 1;
+        self.add_root(index);
+
+        RankToken {
+            index,
+            heap: self.id,
+            generation: self.slab.generation(index),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.min == NIL { return None }
+
+        let min = self.min;
+        let position = self.roots.iter().position(|&root| root == min)
+            .expect("The front of the heap is not a root");
+        self.roots.swap_remove(position);
+
+        // Every node on the right spine of the left child becomes the
+        // root of its own half tree.
+        let mut child = self.slab[min].left;
+        while child != NIL {
+            let next = self.slab[child].right;
+            let rank = self.rank(self.slab[child].left) + 1;
+
+            let child_r = &mut self.slab[child];
+            child_r.right = NIL;
+            child_r.parent = NIL;
+            child_r.rank = rank as u32;
+
+            self.roots.push(child);
+            child = next;
+        }
+
+        self.len -= 1;
+        let value = self.slab.remove(min);
+
+        self.min = self.roots.first().cloned().unwrap_or(NIL);
+        self.link_by_rank();
+        Some(value)
+    }
+
+    /// Do not increase the key! See `Heap::decrease_key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn decrease_key<F>(&mut self, token: RankToken<T>, f: F)
+        where F: FnOnce(&mut T),
+    {
+        if let Err(e) = self.try_decrease_key(token, f) {
+            panic!("Unable to decrease key: {}", e);
+        }
+    }
+
+    /// Like `decrease_key`, but reports a stale or foreign token
+    /// instead of panicking. The closure is not called in that case.
+    pub fn try_decrease_key<F>(&mut self, token: RankToken<T>, f: F) -> Result<(), StaleToken>
+        where F: FnOnce(&mut T),
+    {
+        let node = self.resolve(token)?;
+
+        // Apply the change that decreases the key
+        f(self.slab.value_mut(node));
+
+        let parent = self.slab[node].parent;
+        if parent == NIL {
+            // Already a root, so only the minimum can have changed.
+            if self.comes_before(node, self.min) {
+                self.min = node;
+            }
+            return Ok(());
+        }
+
+        // The right child takes the node's place, and the node becomes
+        // a new root together with its left child.
+        let right = self.slab[node].right;
+        if self.slab[parent].left == node {
+            self.slab[parent].left = right;
+        } else {
+            self.slab[parent].right = right;
+        }
+        if right != NIL {
+            self.slab[right].parent = parent;
+        }
+
+        let rank = self.rank(self.slab[node].left) + 1;
+        let node_r = &mut self.slab[node];
+        node_r.right = NIL;
+        node_r.parent = NIL;
+        node_r.rank = rank as u32;
+
+        self.restore_ranks(parent);
+        self.add_root(node);
+        Ok(())
+    }
+
+    /// Whether `a` should be popped before `b`, which may be `NIL`.
+    fn comes_before(&self, a: u32, b: u32) -> bool {
+        if b == NIL { return true }
+
+        self.compare.compare(self.slab.value(a), self.slab.value(b)) == Ordering::Less
+    }
+
+    fn add_root(&mut self, root: u32) {
+        // Added before comparing, so a panicking comparator can't lose it.
+        self.roots.push(root);
+        if self.comes_before(root, self.min) {
+            self.min = root;
+        }
+    }
+
+    /// Links two half trees of equal rank, returning the new root.
+    fn link(&mut self, first: u32, second: u32) -> u32 {
+        let (winner, loser) = if self.comes_before(second, first) {
+            (second, first)
+        } else {
+            (first, second)
+        };
+
+        let left = self.slab[winner].left;
+        {
+            let loser_r = &mut self.slab[loser];
+            loser_r.right = left;
+            loser_r.parent = winner;
+        }
+        if left != NIL {
+            self.slab[left].parent = loser;
+        }
+
+        let winner_r = &mut self.slab[winner];
+        winner_r.left = loser;
+        winner_r.rank += 1;
+        winner
+    }
+
+    /// One-pass linking: each half tree is linked with at most one
+    /// other of the same rank, and the results are not linked again
+    /// until the next `pop`.
+    ///
+    /// The half trees are linked in place, so each one stays in `roots`
+    /// and `min` always names a root. A panicking comparator leaves the
+    /// heap out of order, but with every value still reachable.
+    fn link_by_rank(&mut self) {
+        self.buckets.clear();
+
+        let mut idx = 0;
+        while idx < self.roots.len() {
+            let root = self.roots[idx];
+            let rank = self.slab[root].rank as usize;
+            if self.buckets.len() <= rank {
+                self.buckets.resize(rank + 1, NIL);
+            }
+
+            match self.buckets[rank] {
+                NIL => {
+                    self.buckets[rank] = idx as u32;
+                    idx += 1;
+                }
+                other_idx => {
+                    self.buckets[rank] = NIL;
+                    let other = self.roots[other_idx as usize];
+                    let linked = self.link(other, root);
+
+                    if self.min == other || self.min == root {
+                        self.min = linked;
+                    }
+                    // Only unvisited roots follow `idx`, so moving the
+                    // last one into its place keeps the buckets valid.
+                    self.roots[other_idx as usize] = linked;
+                    self.roots.swap_remove(idx);
+                }
+            }
+        }
+
+        for idx in 0..self.roots.len() {
+            let root = self.roots[idx];
+            if self.comes_before(root, self.min) {
+                self.min = root;
+            }
+        }
+    }
+
+    /// Lowers ranks from `node` upwards after a child was cut away,
+    /// until the type-2 rank rule holds again.
+    fn restore_ranks(&mut self, mut node: u32) {
+        while node != NIL {
+            let (left, right, parent, rank) = {
+                let node_r = &self.slab[node];
+                (node_r.left, node_r.right, node_r.parent, node_r.rank as i64)
+            };
+            let left_rank = self.rank(left);
+
+            if parent == NIL {
+                // A root has no right child and is one above its left.
+                self.slab[node].rank = (left_rank + 1) as u32;
+                return;
+            }
+
+            let right_rank = self.rank(right);
+            let new_rank = if (left_rank - right_rank).abs() > 1 {
+                left_rank.max(right_rank)
+            } else {
+                left_rank.max(right_rank) + 1
+            };
+
+            if new_rank >= rank { return }
+
+            self.slab[node].rank = new_rank as u32;
+            node = parent;
+        }
+    }
+}
+
+impl<T, C> RankPairingHeap<T, C> {
+    /// The number of values in the heap.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The value that `pop` would return next.
+    pub fn peek(&self) -> Option<&T> {
+        if self.min == NIL { return None }
+        Some(self.slab.value(self.min))
+    }
+
+    /// The current value for a token.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    pub fn get(&self, token: RankToken<T>) -> &T {
+        match self.try_get(token) {
+            Some(value) => value,
+            None => panic!("Unable to get value: {}", StaleToken),
+        }
+    }
+
+    /// Like `get`, but returns `None` for a stale or foreign token.
+    pub fn try_get(&self, token: RankToken<T>) -> Option<&T> {
+        let index = self.resolve(token).ok()?;
+        Some(self.slab.value(index))
+    }
+
+    fn resolve(&self, token: RankToken<T>) -> Result<u32, StaleToken> {
+        if token.heap != self.id { return Err(StaleToken) }
+        self.slab.resolve(token.index, token.generation)
+    }
+
+    /// The rank of a node, or -1 for a missing child.
+    fn rank(&self, index: u32) -> i64 {
+        if index == NIL { -1 } else { self.slab[index].rank as i64 }
+    }
+}
+
+impl<T> Default for RankPairingHeap<T>
+    where T: Ord,
+{
+    fn default() -> Self {
+        RankPairingHeap::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Ordering;
+
+    use super::NIL;
+    use unwind;
+    use {Max, RankPairingHeap, RankToken, StaleToken};
+
+    /// Checks the links, half ordering and type-2 rank rule of every
+    /// half tree, returning the number of nodes found. A heap that saw a
+    /// comparator panic may be out of order, so `ordered` can skip that.
+    fn check_structure<T: Ord, C>(h: &RankPairingHeap<T, C>, ordered: bool) -> usize {
+        let mut count = h.roots.len();
+        let mut stack = Vec::new();
+
+        for &root in &h.roots {
+            let root_r = &h.slab[root];
+            assert_eq!(NIL, root_r.parent);
+            assert_eq!(NIL, root_r.right);
+            assert_eq!(h.rank(root_r.left) + 1, root_r.rank as i64);
+            assert!(!ordered || h.slab.value(h.min) <= h.slab.value(root));
+            stack.push((root, root_r.left));
+        }
+
+        while let Some((half_root, node)) = stack.pop() {
+            if node == NIL { continue }
+            count += 1;
+
+            let node_r = &h.slab[node];
+            assert!(!ordered || h.slab.value(half_root) <= h.slab.value(node));
+
+            let rank = node_r.rank as i64;
+            let mut differences = [rank - h.rank(node_r.left), rank - h.rank(node_r.right)];
+            differences.sort();
+            assert!(differences == [1, 1] || differences == [1, 2] || (differences[0] == 0 && differences[1] >= 1),
+                    "Rank rule broken: {:?}", differences);
+
+            for &child in &[node_r.left, node_r.right] {
+                if child != NIL {
+                    assert_eq!(node, h.slab[child].parent);
+                }
+            }
+            // Everything under the left child comes after this node,
+            // while the right child only comes after the half tree root.
+            stack.push((node, node_r.left));
+            stack.push((half_root, node_r.right));
+        }
+
+        count
+    }
+
+    #[test]
+    fn empty_heap_pops_none() {
+        let mut h = RankPairingHeap::<u8>::new();
+        assert_eq!(None, h.pop());
+        assert_eq!(None, h.peek());
+        assert!(h.is_empty());
+    }
+
+    #[test]
+    fn many_values_inserted_returns_them_in_order() {
+        let mut h = RankPairingHeap::new();
+        for i in (0..123).rev() { h.push(i); }
+        for i in 0..123 { h.push(i); }
+        assert_eq!(246, h.len());
+
+        for i in 0..123 {
+            assert_eq!(Some(&i), h.peek());
+            assert_eq!(Some(i), h.pop());
+            assert_eq!(Some(i), h.pop());
+        }
+        assert_eq!(None, h.pop());
+        assert!(h.is_empty());
+    }
+
+    #[test]
+    fn decreasing_keys_anywhere_keeps_the_order() {
+        let mut h = RankPairingHeap::new();
+        let tokens: Vec<_> = (0..500).map(|i| h.push(i * 7919 % 500 + 1000)).collect();
+
+        // Pop a few first, so the decreased nodes sit deep in half trees.
+        let mut popped = vec![h.pop().unwrap(), h.pop().unwrap()];
+        for (i, &t) in tokens.iter().enumerate().step_by(3) {
+            if h.try_get(t).is_some() {
+                h.decrease_key(t, |v| *v -= 1000 - i);
+                assert_eq!(h.len(), check_structure(&h, true));
+            }
+        }
+
+        let mut expected: Vec<_> = tokens.iter().filter_map(|&t| h.try_get(t).cloned()).collect();
+        expected.sort();
+
+        while let Some(v) = h.pop() {
+            assert_eq!(h.len(), check_structure(&h, true));
+            popped.push(v);
+        }
+        assert_eq!(expected, popped.split_off(2));
+    }
+
+    #[test]
+    fn decreasing_a_root_updates_the_front() {
+        let mut h = RankPairingHeap::new();
+        h.push(10);
+        let t = h.push(20);
+
+        h.decrease_key(t, |v| *v = 5);
+        assert_eq!(Some(&5), h.peek());
+        assert_eq!(5, *h.get(t));
+    }
+
+    #[test]
+    fn stale_and_foreign_tokens_are_an_error() {
+        let mut a = RankPairingHeap::new();
+        let mut b = RankPairingHeap::new();
+
+        let t = a.push(10);
+        b.push(10);
+        assert_eq!(Err(StaleToken), b.try_decrease_key(t, |v| *v = 5));
+
+        assert_eq!(Some(10), a.pop());
+        a.push(20);
+        assert_eq!(Err(StaleToken), a.try_decrease_key(t, |v| *v = 5));
+        assert_eq!(None, a.try_get(t));
+    }
+
+    #[test]
+    fn custom_comparators_are_used() {
+        let mut h = RankPairingHeap::with_comparator(Max);
+        for i in 0..10 { h.push(i); }
+
+        let values: Vec<_> = (0..10).map(|_| h.pop().unwrap()).collect();
+        assert_eq!((0..10).rev().collect::<Vec<_>>(), values);
+    }
+
+    type FragileHeap = RankPairingHeap<u32, fn(&u32, &u32) -> Ordering>;
+
+    /// Runs `op` with a comparator that panics after 0, 1, 2, ...
+    /// comparisons, until it finishes. Every value must stay reachable.
+    fn check_unwinding<F>(op: F)
+        where F: Fn(&mut FragileHeap, &[RankToken<u32>]),
+    {
+        unwind::check(|| {
+            let mut h: FragileHeap = RankPairingHeap::with_comparator(unwind::compare);
+            let tokens: Vec<_> = (0..30).map(|i| h.push(i * 7 % 30 + 100)).collect();
+            h.pop();
+            (h, tokens)
+        }, |&mut (ref mut h, ref tokens)| {
+            op(h, tokens)
+        }, |(mut h, _)| {
+            let len = h.len();
+            assert_eq!(len, check_structure(&h, false));
+            let mut popped = 0;
+            while h.pop().is_some() { popped += 1; }
+            assert_eq!(len, popped);
+        });
+    }
+
+    #[test]
+    fn panicking_comparator_during_push_keeps_every_value() {
+        check_unwinding(|h, _| {
+            for i in 0..10 { h.push(i); }
+        });
+    }
+
+    #[test]
+    fn panicking_comparator_during_pop_keeps_every_value() {
+        check_unwinding(|h, _| {
+            for _ in 0..10 { h.pop(); }
+        });
+    }
+
+    #[test]
+    fn panicking_comparator_during_decrease_key_keeps_every_value() {
+        check_unwinding(|h, tokens| {
+            h.pop();
+            for &t in tokens.iter().step_by(2) {
+                if h.try_get(t).is_some() {
+                    h.decrease_key(t, |v| *v -= 50);
+                }
+            }
+        });
+    }
+}
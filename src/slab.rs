@@ -0,0 +1,161 @@
+use std::mem;
+use std::ops::{Index, IndexMut};
+
+use StaleToken;
+
+/// The index that stands for no slot at all.
+pub(crate) const NIL: u32 = u32::MAX;
+
+enum Entry<T> {
+    Occupied(T),
+    // Free slots form a list through the index of the next one.
+    Free(u32),
+}
+
+struct Slot<T, L> {
+    entry: Entry<T>,
+    // Bumped every time the slot is freed, invalidating old handles.
+    generation: u32,
+    links: L,
+}
+
+/// Values stored in a `Vec` by `u32` index, each with links `L` to
+/// other slots. Freed slots are reused, and their generation is bumped
+/// so that a handle made of an index and a generation goes stale.
+///
+/// Indexing a slab gives the links of a slot.
+pub(crate) struct Slab<T, L> {
+    slots: Vec<Slot<T, L>>,
+    free: u32,
+}
+
+impl<T, L> Slab<T, L>
+    where L: Default,
+{
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Slab {
+            slots: Vec::with_capacity(capacity),
+            free: NIL,
+        }
+    }
+
+    /// Stores a value with default links, returning its index.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the slab would need more than `u32::MAX - 1` slots.
+    pub(crate) fn insert(&mut self, value: T) -> u32 {
+        if self.free != NIL {
+            let index = self.free;
+            let slot = &mut self.slots[index as usize];
+            self.free = match slot.entry {
+                Entry::Free(next) => next,
+                Entry::Occupied(_) => unreachable!("Free slot has a value"),
+            };
+            slot.entry = Entry::Occupied(value);
+            return index;
+        }
+
+        let index = self.slots.len();
+        assert!(index < NIL as usize, "Heap has too many values");
+
+        self.slots.push(Slot {
+            entry: Entry::Occupied(value),
+            generation: 0,
+            links: L::default(),
+        });
+        index as u32
+    }
+
+    /// Takes the value out of a slot that is no longer linked from any
+    /// other, and frees the slot.
+    pub(crate) fn remove(&mut self, index: u32) -> T {
+        let slot = &mut self.slots[index as usize];
+
+        let value = match mem::replace(&mut slot.entry, Entry::Free(self.free)) {
+            Entry::Occupied(value) => value,
+            Entry::Free(_) => panic!("Removed slot has no value"),
+        };
+        slot.generation = slot.generation.wrapping_add(1);
+        slot.links = L::default();
+        self.free = index;
+
+        value
+    }
+}
+
+impl<T, L> Slab<T, L> {
+    /// The index, if it still holds the value of that generation.
+    pub(crate) fn resolve(&self, index: u32, generation: u32) -> Result<u32, StaleToken> {
+        match self.slots.get(index as usize) {
+            Some(&Slot { entry: Entry::Occupied(_), generation: g, .. }) if g == generation => Ok(index),
+            _ => Err(StaleToken),
+        }
+    }
+
+    pub(crate) fn generation(&self, index: u32) -> u32 {
+        self.slots[index as usize].generation
+    }
+
+    pub(crate) fn value(&self, index: u32) -> &T {
+        match self.slots[index as usize].entry {
+            Entry::Occupied(ref value) => value,
+            Entry::Free(_) => panic!("Linked slot has no value"),
+        }
+    }
+
+    pub(crate) fn value_mut(&mut self, index: u32) -> &mut T {
+        match self.slots[index as usize].entry {
+            Entry::Occupied(ref mut value) => value,
+            Entry::Free(_) => panic!("Linked slot has no value"),
+        }
+    }
+}
+
+impl<T, L> Index<u32> for Slab<T, L> {
+    type Output = L;
+
+    fn index(&self, index: u32) -> &L {
+        &self.slots[index as usize].links
+    }
+}
+
+impl<T, L> IndexMut<u32> for Slab<T, L> {
+    fn index_mut(&mut self, index: u32) -> &mut L {
+        &mut self.slots[index as usize].links
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Slab, NIL};
+    use StaleToken;
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Link(u32);
+
+    #[test]
+    fn freed_slots_are_reused_with_a_new_generation() {
+        let mut slab: Slab<_, Link> = Slab::with_capacity(0);
+        let a = slab.insert("a");
+        let b = slab.insert("b");
+        slab[a].0 = b;
+
+        let generation = slab.generation(a);
+        assert_eq!("a", slab.remove(a));
+        assert_eq!(Err(StaleToken), slab.resolve(a, generation));
+
+        let c = slab.insert("c");
+        assert_eq!(a, c);
+        assert_eq!(Link(0), slab[c]);
+        assert_eq!(Ok(c), slab.resolve(c, slab.generation(c)));
+        assert_eq!("c", *slab.value(c));
+    }
+
+    #[test]
+    fn unknown_indexes_are_stale() {
+        let slab: Slab<u8, Link> = Slab::with_capacity(0);
+        assert_eq!(Err(StaleToken), slab.resolve(0, 0));
+        assert_eq!(Err(StaleToken), slab.resolve(NIL, 0));
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::BinaryHeap;
+use std::fmt;
+
+use {CombineStrategy, Compare, Heap, RankPairingHeap, RankToken, StaleToken, Token};
+
+/// The token API that `Heap` and `RankPairingHeap` share, so code can
+/// be written once for either. `Token` names the type each heap hands
+/// out, which lets one type alias switch even code that stores tokens.
+pub trait TokenHeap<T> {
+    /// What `push` returns to refer to a value later.
+    type Token: Copy;
+
+    fn push(&mut self, value: T) -> Self::Token;
+
+    fn pop(&mut self) -> Option<T>;
+
+    /// The value that `pop` would return next.
+    fn peek(&self) -> Option<&T>;
+
+    /// The number of values in the heap.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The current value for a token, or `None` if it is stale or came
+    /// from a different heap.
+    fn try_get(&self, token: Self::Token) -> Option<&T>;
+
+    /// Moves a value towards the front. Reports a stale or foreign
+    /// token instead of calling the closure.
+    fn try_decrease_key<F>(&mut self, token: Self::Token, f: F) -> Result<(), StaleToken>
+        where F: FnOnce(&mut T);
+
+    /// Like `try_decrease_key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token's value has already been popped or the
+    /// token came from a different heap.
+    fn decrease_key<F>(&mut self, token: Self::Token, f: F)
+        where F: FnOnce(&mut T),
+    {
+        if let Err(e) = self.try_decrease_key(token, f) {
+            panic!("Unable to decrease key: {}", e);
+        }
+    }
+}
+
+impl<T, C, S> TokenHeap<T> for Heap<T, C, S>
+    where C: Compare<T>,
+          S: CombineStrategy,
+{
+    type Token = Token<T>;
+
+    fn push(&mut self, value: T) -> Token<T> {
+        Heap::push(self, value)
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        Heap::pop(self)
+    }
+
+    fn peek(&self) -> Option<&T> {
+        Heap::peek(self)
+    }
+
+    fn len(&self) -> usize {
+        Heap::len(self)
+    }
+
+    fn try_get(&self, token: Token<T>) -> Option<&T> {
+        Heap::try_get(self, token)
+    }
+
+    fn try_decrease_key<F>(&mut self, token: Token<T>, f: F) -> Result<(), StaleToken>
+        where F: FnOnce(&mut T),
+    {
+        Heap::try_decrease_key(self, token, f)
+    }
+}
+
+impl<T, C> TokenHeap<T> for RankPairingHeap<T, C>
+    where C: Compare<T>,
+{
+    type Token = RankToken<T>;
+
+    fn push(&mut self, value: T) -> RankToken<T> {
+        RankPairingHeap::push(self, value)
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        RankPairingHeap::pop(self)
+    }
+
+    fn peek(&self) -> Option<&T> {
+        RankPairingHeap::peek(self)
+    }
+
+    fn len(&self) -> usize {
+        RankPairingHeap::len(self)
+    }
+
+    fn try_get(&self, token: RankToken<T>) -> Option<&T> {
+        RankPairingHeap::try_get(self, token)
+    }
+
+    fn try_decrease_key<F>(&mut self, token: RankToken<T>, f: F) -> Result<(), StaleToken>
+        where F: FnOnce(&mut T),
+    {
+        RankPairingHeap::try_decrease_key(self, token, f)
+    }
+}
+
+impl<T, C, S> Default for Heap<T, C, S>
+    where C: Compare<T> + Default,
+          S: CombineStrategy + Default,
+{
+    fn default() -> Self {
+        Heap::with_strategy(C::default(), S::default())
+    }
+}
+
+/// Shows the value `pop` would return next, followed by every value in
+/// arbitrary order.
+impl<T, C, S> fmt::Debug for Heap<T, C, S>
+    where T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Heap")
+            .field("peek", &self.peek())
+            .field("values", &DebugValues(self))
+            .finish()
+    }
+}
+
+struct DebugValues<'a, T: 'a, C: 'a, S: 'a>(&'a Heap<T, C, S>);
+
+impl<'a, T, C, S> fmt::Debug for DebugValues<'a, T, C, S>
+    where T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.0.iter()).finish()
+    }
+}
+
+/// Copies every value into a tree of the same shape. The clone is a
+/// separate heap, so tokens from the original cannot be used with it.
+impl<T, C, S> Clone for Heap<T, C, S>
+    where T: Clone,
+          C: Clone,
+          S: Clone,
+{
+    fn clone(&self) -> Self {
+        let mut heap = Heap::empty(self.compare.clone(), self.strategy.clone());
+        heap.lazy_insert = self.lazy_insert;
+        heap.stable = self.stable;
+        heap.next_seq = self.next_seq;
+        if self.root.is_null() { return heap }
+
+        let root_r = unsafe { &*self.root };
+        heap.root = heap.allocate((*root_r.value).clone());
+        unsafe { (*heap.root).seq = root_r.seq };
+        heap.len = 1;
+
+        // Each copied node is linked in as soon as it exists, so if a
+        // `clone` panics the partial heap is still dropped correctly.
+        let mut stack = vec![(self.root, heap.root)];
+
+        while let Some((src, dst)) = stack.pop() {
+            let src_r = unsafe { &*src };
+
+            if let Some(child_r) = unsafe { src_r.first_child.as_ref() } {
+                let child = heap.allocate((*child_r.value).clone());
+                unsafe {
+                    (*child).seq = child_r.seq;
+                    (*dst).first_child = child;
+                    (*child).prev = dst;
+                }
+                heap.len += 1;
+                stack.push((src_r.first_child, child));
+            }
+
+            if let Some(next_r) = unsafe { src_r.next.as_ref() } {
+                let next = heap.allocate((*next_r.value).clone());
+                unsafe {
+                    (*next).seq = next_r.seq;
+                    (*dst).next = next;
+                    (*next).prev = dst;
+                }
+                heap.len += 1;
+                stack.push((src_r.next, next));
+            }
+        }
+
+        heap
+    }
+}
+
+impl<T, C, S> From<BinaryHeap<T>> for Heap<T, C, S>
+    where C: Compare<T> + Default,
+          S: CombineStrategy + Default,
+{
+    fn from(heap: BinaryHeap<T>) -> Self {
+        heap.into_vec().into()
+    }
+}
+
+impl<T, C, S> From<Heap<T, C, S>> for BinaryHeap<T>
+    where T: Ord,
+          C: Compare<T>,
+          S: CombineStrategy,
+{
+    fn from(heap: Heap<T, C, S>) -> Self {
+        heap.into_vec().into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BinaryHeap;
+    use Heap;
+
+    #[derive(Default, Debug, Clone)]
+    struct Scheduler {
+        queue: Heap<u32>,
+    }
+
+    #[test]
+    fn heaps_can_be_derived_through() {
+        let mut s = Scheduler::default();
+        s.queue.push(1);
+
+        let t = s.clone();
+        assert_eq!(Some(&1), t.queue.peek());
+        assert!(format!("{:?}", t).starts_with("Scheduler { queue: Heap {"));
+    }
+
+    #[test]
+    fn debug_shows_the_front_and_all_values() {
+        let mut h = Heap::new();
+        assert_eq!("Heap { peek: None, values: [] }", format!("{:?}", h));
+
+        h.push(2);
+        h.push(1);
+        let debug = format!("{:?}", h);
+        assert!(debug.starts_with("Heap { peek: Some(1), values: ["));
+        assert!(debug.contains('2'));
+    }
+
+    #[test]
+    fn clones_are_independent_deep_copies() {
+        let mut h = Heap::new();
+        for i in (0..20).rev() { h.push(i.to_string()); }
+        h.pop();
+        let t = h.push("a".to_string());
+
+        let c = h.clone();
+        // Iteration order follows the tree shape.
+        assert_eq!(h.iter().collect::<Vec<_>>(), c.iter().collect::<Vec<_>>());
+
+        h.decrease_key(t, |v| v.clear());
+        h.pop();
+
+        assert_eq!(20, c.len());
+        assert_eq!(19, h.len());
+        assert_eq!(Some(&"1".to_string()), c.peek());
+
+        let mut expected: Vec<_> = (1..20).map(|i| i.to_string()).collect();
+        expected.push("a".to_string());
+        expected.sort();
+        assert_eq!(expected, c.into_sorted_vec());
+    }
+
+    #[test]
+    fn converting_to_and_from_binary_heap() {
+        let b: BinaryHeap<_> = vec![3, 1, 2].into();
+
+        let h: Heap<_> = b.into();
+        assert_eq!(Some(&1), h.peek());
+
+        let b: BinaryHeap<_> = h.into();
+        assert_eq!(vec![1, 2, 3], b.into_sorted_vec());
+    }
+}
@@ -0,0 +1,62 @@
+//! Panic injection for the tests that check each heap survives a
+//! panicking comparison.
+//!
+//! Comparisons burn fuel from a thread-local tank and panic once it is
+//! empty, so any comparator or `Ord` impl can be made fragile.
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::panic::{self, AssertUnwindSafe};
+
+thread_local!(static FUEL: Cell<usize> = const { Cell::new(usize::MAX) });
+
+/// Uses up one comparison's worth of fuel, panicking if there is none.
+pub fn burn() {
+    FUEL.with(|fuel| {
+        if fuel.get() == 0 { panic!("Comparison ran out of fuel") }
+        fuel.set(fuel.get() - 1);
+    });
+}
+
+/// Orders like `Ord`, burning fuel first.
+pub fn compare<T: Ord>(a: &T, b: &T) -> Ordering {
+    burn();
+    a.cmp(b)
+}
+
+/// A value whose `Ord` impl burns fuel, for heaps without comparators.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Fragile<T>(pub T);
+
+impl<T: Ord> PartialOrd for Fragile<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Ord> Ord for Fragile<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare(&self.0, &other.0)
+    }
+}
+
+/// Runs `op` on a fresh state from `setup` with fuel for 0, 1, 2, ...
+/// comparisons, until it finishes. After every run, whether it panicked
+/// or not, `check` gets the state to make sure nothing was lost.
+pub fn check<T, S, O, C>(setup: S, op: O, check: C)
+    where S: Fn() -> T,
+          O: Fn(&mut T),
+          C: Fn(T),
+{
+    for limit in 0..1000 {
+        let mut state = setup();
+
+        FUEL.with(|fuel| fuel.set(limit));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| op(&mut state)));
+        FUEL.with(|fuel| fuel.set(usize::MAX));
+
+        check(state);
+        if result.is_ok() { return }
+    }
+    panic!("Operation never finished");
+}